@@ -0,0 +1,347 @@
+//! peer address manager
+//!
+//! Addresses learned from the network are kept in two tables. `new` holds
+//! addresses we heard about but never connected to, `tried` holds addresses we
+//! reached at least once. Both tables are split into buckets chosen by a keyed
+//! hash of the address group and the group of the peer that told us about it,
+//! so a single peer (or a single network range) can only fill a small part of
+//! the book.
+
+use super::*;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rand::rngs::OsRng;
+use rand::seq::SliceRandom;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::SystemTime;
+use log::{debug, info};
+
+const NEW_BUCKET_COUNT: u64 = 64;
+const TRIED_BUCKET_COUNT: u64 = 16;
+const BUCKET_SIZE: usize = 16;
+/// number of new buckets a single source group can place addresses in
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 8;
+/// number of tried buckets a single address group can land in
+const TRIED_BUCKETS_PER_GROUP: u64 = 4;
+/// addresses not seen for this long are dropped
+const ADDR_HORIZON: u64 = 30 * 24 * 60 * 60;
+/// give up on addresses that failed this many times in a row without ever succeeding
+const MAX_RETRIES: u32 = 3;
+/// timestamps further than this in the future are not trusted
+const MAX_TIME_DRIFT: u64 = 10 * 60;
+
+const KEY_ENTRY: &str = "KEY";
+
+/// AddrInfo is what the address book remembers about a peer address
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddrInfo {
+    pub addr: String,
+    pub source: String,
+    pub last_seen: u64,
+    pub last_tried: u64,
+    pub last_success: u64,
+    pub attempts: u32,
+    pub tried: bool,
+}
+
+/// AddrMan is the persistent peer address book
+pub struct AddrMan {
+    path: String,
+    key: Vec<u8>,
+    entries: HashMap<String, AddrInfo>,
+    new_buckets: Vec<Vec<String>>,
+    tried_buckets: Vec<Vec<String>>,
+}
+
+impl AddrMan {
+    /// New loads the address book stored at path
+    pub fn new(path: &str) -> Result<AddrMan> {
+        let mut am = AddrMan {
+            path: path.to_string(),
+            key: Vec::new(),
+            entries: HashMap::new(),
+            new_buckets: vec![Vec::new(); NEW_BUCKET_COUNT as usize],
+            tried_buckets: vec![Vec::new(); TRIED_BUCKET_COUNT as usize],
+        };
+
+        let db = sled::open(path)?;
+        am.key = match db.get(KEY_ENTRY)? {
+            Some(k) => k.to_vec(),
+            None => {
+                let mut key = vec![0; 32];
+                OsRng.fill_bytes(&mut key);
+                key
+            }
+        };
+        for item in db.iter() {
+            let (k, v) = item?;
+            if k == KEY_ENTRY.as_bytes() {
+                continue;
+            }
+            let info: AddrInfo = bincode::deserialize(&v)?;
+            am.place(info);
+        }
+        drop(db);
+        info!("Loaded {} peer addresses", am.entries.len());
+        Ok(am)
+    }
+
+    /// SaveAll writes the address book back to disk
+    pub fn save_all(&self) -> Result<()> {
+        let db = sled::open(&self.path)?;
+        db.clear()?;
+        db.insert(KEY_ENTRY, self.key.clone())?;
+        for (addr, info) in &self.entries {
+            db.insert(addr.as_bytes(), bincode::serialize(info)?)?;
+        }
+        db.flush()?;
+        drop(db);
+        Ok(())
+    }
+
+    pub fn get(&self, addr: &str) -> Option<&AddrInfo> {
+        self.entries.get(addr)
+    }
+
+    /// Add records an address announced by source, returns true if it is new
+    pub fn add(&mut self, addr: &str, source: &str, last_seen: u64) -> bool {
+        let now = now();
+        // never trust a peer's clock too much
+        let last_seen = if last_seen > now + MAX_TIME_DRIFT {
+            now.saturating_sub(5 * 24 * 60 * 60)
+        } else {
+            last_seen.min(now)
+        };
+        if now.saturating_sub(last_seen) > ADDR_HORIZON {
+            return false;
+        }
+
+        if let Some(info) = self.entries.get_mut(addr) {
+            if last_seen > info.last_seen {
+                info.last_seen = last_seen;
+            }
+            return false;
+        }
+
+        debug!("add peer address: {} from: {}", addr, source);
+        self.place(AddrInfo {
+            addr: addr.to_string(),
+            source: source.to_string(),
+            last_seen,
+            last_tried: 0,
+            last_success: 0,
+            attempts: 0,
+            tried: false,
+        });
+        true
+    }
+
+    /// Attempt marks a failed connection attempt to addr, it returns true once
+    /// addr failed MAX_RETRIES times in a row and should no longer be used as a peer
+    pub fn attempt(&mut self, addr: &str) -> bool {
+        if !self.entries.contains_key(addr) {
+            self.add(addr, addr, now());
+        }
+        let info = match self.entries.get_mut(addr) {
+            Some(info) => info,
+            None => return true,
+        };
+        info.last_tried = now();
+        info.attempts += 1;
+        if info.attempts < MAX_RETRIES {
+            return false;
+        }
+        if !info.tried {
+            debug!("forget unreachable peer address: {}", addr);
+            self.remove(addr);
+        }
+        true
+    }
+
+    /// Good marks addr as reachable and moves it to the tried table
+    pub fn good(&mut self, addr: &str) {
+        let now = now();
+        let info = match self.entries.get(addr) {
+            Some(info) => info.clone(),
+            None => AddrInfo {
+                addr: addr.to_string(),
+                source: addr.to_string(),
+                last_seen: now,
+                last_tried: 0,
+                last_success: 0,
+                attempts: 0,
+                tried: false,
+            },
+        };
+        self.remove(addr);
+        self.place(AddrInfo {
+            last_seen: now,
+            last_tried: now,
+            last_success: now,
+            attempts: 0,
+            tried: true,
+            ..info
+        });
+    }
+
+    /// Select returns up to count addresses, preferring ones we reached before
+    pub fn select(&self, count: usize) -> Vec<String> {
+        let mut rng = rand::thread_rng();
+        let mut tried: Vec<&AddrInfo> = self.entries.values().filter(|i| i.tried).collect();
+        let mut new: Vec<&AddrInfo> = self.entries.values().filter(|i| !i.tried).collect();
+        tried.shuffle(&mut rng);
+        new.shuffle(&mut rng);
+        tried
+            .into_iter()
+            .chain(new)
+            .take(count)
+            .map(|i| i.addr.clone())
+            .collect()
+    }
+
+    /// Sample returns up to count fresh addresses with their last seen time for gossip
+    pub fn sample(&self, count: usize) -> Vec<(String, u64)> {
+        let mut rng = rand::thread_rng();
+        let now = now();
+        let mut fresh: Vec<&AddrInfo> = self
+            .entries
+            .values()
+            .filter(|i| now.saturating_sub(i.last_seen) <= ADDR_HORIZON)
+            .collect();
+        fresh.shuffle(&mut rng);
+        fresh
+            .into_iter()
+            .take(count)
+            .map(|i| (i.addr.clone(), i.last_seen))
+            .collect()
+    }
+
+    /// Place puts info into its bucket, evicting the stalest entry if the bucket is full
+    fn place(&mut self, info: AddrInfo) {
+        let bucket = if info.tried {
+            let idx = self.tried_bucket(&info.addr) as usize;
+            &mut self.tried_buckets[idx]
+        } else {
+            let idx = self.new_bucket(&info.addr, &info.source) as usize;
+            &mut self.new_buckets[idx]
+        };
+
+        if bucket.len() >= BUCKET_SIZE {
+            let entries = &self.entries;
+            let (idx, _) = bucket
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, a)| entries.get(*a).map(|i| i.last_seen).unwrap_or(0))
+                .unwrap();
+            let evicted = bucket.remove(idx);
+            debug!("evict peer address: {}", evicted);
+            self.entries.remove(&evicted);
+        }
+        bucket.push(info.addr.clone());
+        self.entries.insert(info.addr.clone(), info);
+    }
+
+    fn remove(&mut self, addr: &str) {
+        if let Some(info) = self.entries.remove(addr) {
+            let bucket = if info.tried {
+                let idx = self.tried_bucket(addr) as usize;
+                &mut self.tried_buckets[idx]
+            } else {
+                let idx = self.new_bucket(addr, &info.source) as usize;
+                &mut self.new_buckets[idx]
+            };
+            bucket.retain(|a| a != addr);
+        }
+    }
+
+    fn new_bucket(&self, addr: &str, source: &str) -> u64 {
+        let source_group = group(source);
+        let slot = self.keyed_hash(&[&group(addr), &source_group]) % NEW_BUCKETS_PER_SOURCE_GROUP;
+        self.keyed_hash(&[&source_group, &slot.to_string()]) % NEW_BUCKET_COUNT
+    }
+
+    fn tried_bucket(&self, addr: &str) -> u64 {
+        let slot = self.keyed_hash(&[addr]) % TRIED_BUCKETS_PER_GROUP;
+        self.keyed_hash(&[&group(addr), &slot.to_string()]) % TRIED_BUCKET_COUNT
+    }
+
+    fn keyed_hash(&self, parts: &[&str]) -> u64 {
+        let mut hasher = Sha256::new();
+        hasher.input(&self.key);
+        for p in parts {
+            hasher.input(p.as_bytes());
+            hasher.input(&[0]);
+        }
+        let mut re: [u8; 32] = [0; 32];
+        hasher.result(&mut re);
+        let mut n: [u8; 8] = [0; 8];
+        n.copy_from_slice(&re[..8]);
+        u64::from_le_bytes(n)
+    }
+}
+
+/// Group returns the network range an address belongs to: /16 for IPv4,
+/// /32 for IPv6, and the host name itself otherwise
+pub fn group(addr: &str) -> String {
+    let host = match addr.rfind(':') {
+        Some(i) => &addr[..i],
+        None => addr,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let o = ip.octets();
+            format!("{}.{}", o[0], o[1])
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let s = ip.segments();
+            format!("{:x}:{:x}", s[0], s[1])
+        }
+        Err(_) => host.to_string(),
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_group() {
+        assert_eq!(group("10.1.2.3:3000"), "10.1");
+        assert_eq!(group("[2001:db8::1]:3000"), "2001:db8");
+        assert_eq!(group("localhost:3000"), "localhost");
+    }
+
+    #[test]
+    fn test_source_cannot_flood_book() {
+        std::fs::remove_dir_all("data/test_peers").ok();
+        let mut am = AddrMan::new("data/test_peers").unwrap();
+        let now = now();
+        for i in 0..10000 {
+            am.add(&format!("10.{}.{}.1:3000", i / 256, i % 256), "6.6.6.6:3000", now);
+        }
+        assert!(am.entries.len() <= (NEW_BUCKETS_PER_SOURCE_GROUP as usize) * BUCKET_SIZE);
+
+        assert!(!am.attempt("5.6.7.8:3000"));
+        assert!(!am.attempt("5.6.7.8:3000"));
+        assert!(am.attempt("5.6.7.8:3000"));
+        assert!(am.get("5.6.7.8:3000").is_none());
+
+        am.good("1.2.3.4:3000");
+        am.save_all().unwrap();
+        let loaded = AddrMan::new("data/test_peers").unwrap();
+        assert_eq!(loaded.entries.len(), am.entries.len());
+        assert!(loaded.get("1.2.3.4:3000").unwrap().tried);
+        std::fs::remove_dir_all("data/test_peers").ok();
+    }
+}
@@ -1,15 +1,21 @@
 
-use crate::{blockchain::Blockchain, transaction::Transaction};
+use crate::transaction::Transaction;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use crate::error::Result;
+use failure::format_err;
 use log::info;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
 use std::time::SystemTime;
 use merkle_cbt::merkle_tree::Merge;
 use  merkle_cbt::merkle_tree::CBMT;
 
 const TARGET_HEXT: usize = 4;
+/// how many nonces are tried between two checks of the abort condition
+const ABORT_CHECK_INTERVAL: u64 = 1024;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
@@ -18,7 +24,7 @@ pub struct Block {
     prev_block_hash: String,
     hash: String,
     height: i32,
-    nonce: i32,
+    nonce: u32,
 }
 
 impl Block {
@@ -38,44 +44,110 @@ impl Block {
         self.hash.clone()
     }
 
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    pub fn get_nonce(&self) -> u32 {
+        self.nonce
+    }
+
     ///new genesis block
     pub fn new_genesis_block(coinbase: Transaction) -> Block {
         Block::new_block(vec![coinbase], String::new(), 0).unwrap()
     }
     pub fn new_block(data: Vec<Transaction>, prev_block_hash: String, height: i32) -> Result<Block> {
+        let mut block = Block::new_template(data, prev_block_hash, height)?;
+        block.run_proof_of_work()?;
+        Ok(block)
+    }
+
+    /// NewTemplate creates a block that still needs its proof of work
+    pub fn new_template(data: Vec<Transaction>, prev_block_hash: String, height: i32) -> Result<Block> {
         let timestamp: u128 = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
-        let mut block = Block {
-            timestamp: timestamp,
+        Ok(Block {
+            timestamp,
             transactions: data,
             prev_block_hash,
             hash: String::new(),
             height,
             nonce: 0,
-        };
-        block.run_proof_of_work()?;
-        Ok(block)
+        })
     }
+
     fn run_proof_of_work(&mut self) -> Result<()> {
-        info!("Mining the block");
-        while !self.validate()? {
-            self.nonce += 1;
+        self.mine(available_threads(), || false, &AtomicU64::new(0))?;
+        Ok(())
+    }
+
+    /// Mine searches for a valid nonce on `threads` workers until one is found or
+    /// abort returns true, counting every hash tried in hashes. When the whole
+    /// nonce space fails the coinbase extra nonce is bumped and the search starts
+    /// over. Returns whether the block was solved
+    pub fn mine<F: Fn() -> bool + Sync>(
+        &mut self,
+        threads: usize,
+        abort: F,
+        hashes: &AtomicU64,
+    ) -> Result<bool> {
+        info!("Mining the block on {} threads", threads);
+        let threads = threads.max(1) as u64;
+        loop {
+            self.nonce = 0;
+            let header = self.prepare_hash_data()?;
+            let done = AtomicBool::new(false);
+            let solution = AtomicU64::new(u64::MAX);
+            thread::scope(|s| {
+                for first in 0..threads {
+                    let (header, abort, done, solution) = (&header, &abort, &done, &solution);
+                    s.spawn(move || {
+                        search_nonces(header, first, threads, abort, done, solution, hashes)
+                    });
+                }
+            });
+
+            let nonce = solution.load(Ordering::SeqCst);
+            if nonce != u64::MAX {
+                self.nonce = nonce as u32;
+                let data: Vec<u8> = self.prepare_hash_data()?;
+                let mut hasher: Sha256 = Sha256::new();
+                hasher.input(&data[..]);
+                self.hash = hasher.result_str();
+                return Ok(true);
+            }
+            if done.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            self.increment_extra_nonce()?;
         }
-        let data: Vec<u8> = self.prepare_hash_data()?;
-        let mut hasher: Sha256 = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
+    }
+
+    /// IncrementExtraNonce changes the coinbase so the header gets a fresh nonce space
+    fn increment_extra_nonce(&mut self) -> Result<()> {
+        let coinbase = match self.transactions.iter_mut().find(|tx| tx.is_coinbase()) {
+            Some(tx) => tx,
+            None => return Err(format_err!("nonce space exhausted and no coinbase to update")),
+        };
+        let mut extra_nonce: [u8; 8] = [0; 8];
+        if coinbase.vin[0].signature.len() == 8 {
+            extra_nonce.copy_from_slice(&coinbase.vin[0].signature);
+        }
+        let extra_nonce = u64::from_le_bytes(extra_nonce) + 1;
+        info!("nonce space exhausted, extra nonce: {}", extra_nonce);
+        coinbase.vin[0].signature = extra_nonce.to_le_bytes().to_vec();
+        coinbase.id = coinbase.hash()?;
         Ok(())
     }
 
     ///HashTransactions returns a hash of the transactions in the block
     fn hash_transactions(self) -> Result<Vec<u8>> {
         let mut transactions = Vec::new();
-        for mut tx in self.transactions{
+        for tx in self.transactions {
             transactions.push(tx.hash()?.as_bytes().to_owned());
         }
-        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&*transactions);
+        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&transactions);
 
         Ok(tree.root())
     }
@@ -92,15 +164,230 @@ impl Block {
         Ok(bytes)
     }
 
-    fn validate(&self) -> Result<bool> {
+    /// Validate checks the proof of work, and that the block hash is the one of its contents
+    pub fn validate(&self) -> Result<bool> {
         let data: Vec<u8> = self.prepare_hash_data()?;
         let mut hasher: Sha256 = Sha256::new();
         hasher.input(&data[..]);
+        let hash = hasher.result_str();
         let mut vec1: Vec<u8> = vec![];
-        vec1.resize(TARGET_HEXT, '0' as u8);
-        //println("{:?}", vec1);
-        Ok(&hasher.result_str()[0..TARGET_HEXT] == String::from_utf8(vec1)?)
+        vec1.resize(TARGET_HEXT, b'0');
+        Ok(hash == self.hash && hash[0..TARGET_HEXT] == String::from_utf8(vec1)?)
+    }
+}
+
+/// CompactBlock is a block header with short ids in place of the transactions,
+/// which the receiver mostly has in its mempool already. The coinbase, that no
+/// one else has, is sent in full
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactBlock {
+    timestamp: u128,
+    prev_block_hash: String,
+    hash: String,
+    height: i32,
+    nonce: u32,
+    /// salt makes the short ids differ from block to block
+    salt: u64,
+    /// short_ids of the transactions not prefilled, in block order
+    short_ids: Vec<u64>,
+    /// prefilled transactions with their index in the block
+    prefilled: Vec<(usize, Transaction)>,
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> CompactBlock {
+        let salt = rand::random();
+        let mut short_ids = Vec::new();
+        let mut prefilled = Vec::new();
+        for (i, tx) in block.transactions.iter().enumerate() {
+            if tx.is_coinbase() {
+                prefilled.push((i, tx.clone()));
+            } else {
+                short_ids.push(short_id(&block.hash, salt, &tx.id));
+            }
+        }
+        CompactBlock {
+            timestamp: block.timestamp,
+            prev_block_hash: block.prev_block_hash.clone(),
+            hash: block.hash.clone(),
+            height: block.height,
+            nonce: block.nonce,
+            salt,
+            short_ids,
+            prefilled,
+        }
+    }
+
+    pub fn get_hash(&self) -> String {
+        self.hash.clone()
+    }
+}
+
+/// short_id is the 6 bytes id of txid in the compact block hash, keyed by salt
+fn short_id(block_hash: &str, salt: u64, txid: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.input(block_hash.as_bytes());
+    hasher.input(&salt.to_le_bytes());
+    hasher.input(txid.as_bytes());
+    let mut hash: [u8; 32] = [0; 32];
+    hasher.result(&mut hash);
+    let mut id: [u8; 8] = [0; 8];
+    id[..6].copy_from_slice(&hash[..6]);
+    u64::from_le_bytes(id)
+}
+
+/// PartialBlock is a compact block being rebuilt, with the transactions found so far
+pub struct PartialBlock {
+    header: CompactBlock,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// New fills the block with the prefilled transactions and the mempool ones
+    /// matching a short id. A short id matching several transactions is left to
+    /// be requested
+    pub fn new(header: CompactBlock, mempool: &HashMap<String, Transaction>) -> PartialBlock {
+        let mut by_short_id: HashMap<u64, Option<&Transaction>> = HashMap::new();
+        for tx in mempool.values() {
+            by_short_id
+                .entry(short_id(&header.hash, header.salt, &tx.id))
+                .and_modify(|found| *found = None)
+                .or_insert(Some(tx));
+        }
+
+        let count = header.short_ids.len() + header.prefilled.len();
+        let mut transactions: Vec<Option<Transaction>> = vec![None; count];
+        for (i, tx) in &header.prefilled {
+            if let Some(slot) = transactions.get_mut(*i) {
+                *slot = Some(tx.clone());
+            }
+        }
+        let mut short_ids = header.short_ids.iter();
+        for slot in transactions.iter_mut().filter(|t| t.is_none()) {
+            match short_ids.next() {
+                Some(id) => *slot = by_short_id.get(id).cloned().flatten().cloned(),
+                None => break,
+            }
+        }
+        PartialBlock { header, transactions }
+    }
+
+    pub fn get_hash(&self) -> String {
+        self.header.get_hash()
+    }
+
+    /// Missing returns the indexes of the transactions still unknown
+    pub fn missing(&self) -> Vec<usize> {
+        (0..self.transactions.len())
+            .filter(|i| self.transactions[*i].is_none())
+            .collect()
+    }
+
+    /// Fill sets the missing transactions, given in the order of missing
+    pub fn fill(&mut self, txs: Vec<Transaction>) -> Result<()> {
+        let missing = self.missing();
+        if txs.len() != missing.len() {
+            return Err(format_err!(
+                "{} transactions are missing from block {}, got {}",
+                missing.len(),
+                self.header.hash,
+                txs.len()
+            ));
+        }
+        for (i, tx) in missing.into_iter().zip(txs) {
+            self.transactions[i] = Some(tx);
+        }
+        Ok(())
+    }
+
+    /// ToBlock assembles the block once complete, checking that its transactions
+    /// hash to the header, which a short id collision would break
+    pub fn to_block(&self) -> Result<Block> {
+        let mut transactions = Vec::new();
+        for tx in &self.transactions {
+            match tx {
+                Some(tx) => transactions.push(tx.clone()),
+                None => return Err(format_err!("block {} is not complete", self.header.hash)),
+            }
+        }
+        let mut block = Block {
+            timestamp: self.header.timestamp,
+            transactions,
+            prev_block_hash: self.header.prev_block_hash.clone(),
+            hash: String::new(),
+            height: self.header.height,
+            nonce: self.header.nonce,
+        };
+        let mut hasher = Sha256::new();
+        hasher.input(&block.prepare_hash_data()?);
+        if hasher.result_str() != self.header.hash {
+            return Err(format_err!("rebuilt block {} does not match its header", self.header.hash));
+        }
+        block.hash = self.header.hash.clone();
+        Ok(block)
+    }
+}
+
+/// available_threads returns the number of threads mining uses by default
+pub fn available_threads() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// search_nonces hashes the serialized header with the nonces first, first + step, ...
+/// only rewriting the trailing nonce bytes, until a worker finds a solution or abort
+/// returns true
+fn search_nonces<F: Fn() -> bool>(
+    header: &[u8],
+    first: u64,
+    step: u64,
+    abort: &F,
+    done: &AtomicBool,
+    solution: &AtomicU64,
+    hashes: &AtomicU64,
+) {
+    let mut data = header.to_vec();
+    let nonce_at = data.len() - 4;
+    let mut hasher = Sha256::new();
+    let mut hash: [u8; 32] = [0; 32];
+    let mut count: u64 = 0;
+    let mut nonce = first;
+
+    while nonce <= u32::MAX as u64 {
+        if count.is_multiple_of(ABORT_CHECK_INTERVAL) {
+            hashes.fetch_add(count, Ordering::Relaxed);
+            count = 0;
+            if done.load(Ordering::Relaxed) {
+                return;
+            }
+            if abort() {
+                done.store(true, Ordering::Relaxed);
+                return;
+            }
+        }
+
+        data[nonce_at..].copy_from_slice(&(nonce as u32).to_le_bytes());
+        hasher.reset();
+        hasher.input(&data);
+        hasher.result(&mut hash);
+        count += 1;
+
+        if meets_target(&hash) {
+            if !done.swap(true, Ordering::SeqCst) {
+                solution.store(nonce, Ordering::SeqCst);
+            }
+            break;
+        }
+        nonce += step;
     }
+    hashes.fetch_add(count, Ordering::Relaxed);
+}
+
+/// meets_target checks that the hex form of hash starts with TARGET_HEXT zeros
+fn meets_target(hash: &[u8]) -> bool {
+    if hash[..TARGET_HEXT / 2].iter().any(|b| *b != 0) {
+        return false;
+    }
+    TARGET_HEXT.is_multiple_of(2) || hash[TARGET_HEXT / 2] < 0x10
 }
 
 struct MergeTX {}
@@ -123,6 +410,8 @@ impl Merge for MergeTX {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::transaction::TXOutput;
 
     #[test]
     fn test_blockchain() {
@@ -145,4 +434,69 @@ mod tests {
             println!("item: {:#?}", item)
         }
     }
+
+    #[test]
+    fn test_mine_abort() {
+        let hashes = AtomicU64::new(0);
+        let mut block = Block::new_template(Vec::new(), String::new(), 0).unwrap();
+        assert!(!block.mine(2, || true, &hashes).unwrap());
+        assert!(block.hash.is_empty());
+
+        let mut block = Block::new_template(Vec::new(), String::new(), 0).unwrap();
+        assert!(block.mine(4, || false, &hashes).unwrap());
+        assert!(block.validate().unwrap());
+        assert_eq!(block.hash.len(), 64);
+        block.nonce += 1;
+        assert!(!block.validate().unwrap());
+        assert!(hashes.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn test_extra_nonce() {
+        let address = bitcoincash_addr::Address {
+            body: vec![0; 20],
+            ..Default::default()
+        };
+        let tx = Transaction::new_coinbase(address.encode().unwrap(), String::from("extra")).unwrap();
+        let mut block = Block::new_template(vec![tx], String::new(), 0).unwrap();
+        let header = block.prepare_hash_data().unwrap();
+        block.increment_extra_nonce().unwrap();
+        block.increment_extra_nonce().unwrap();
+        assert_ne!(block.prepare_hash_data().unwrap(), header);
+        assert_eq!(block.transactions[0].vin[0].signature, 2u64.to_le_bytes().to_vec());
+        assert!(block.transactions[0].is_coinbase());
+    }
+
+    #[test]
+    fn test_compact_block() {
+        let address = bitcoincash_addr::Address {
+            body: vec![0; 20],
+            ..Default::default()
+        };
+        // the block commits to the transaction contents, the ids only name them
+        let tx = |id: &str| Transaction {
+            id: id.to_string(),
+            vin: Vec::new(),
+            vout: vec![TXOutput {
+                value: id.as_bytes()[0] as i32,
+                pub_key_hash: Vec::new(),
+            }],
+        };
+        let coinbase = Transaction::new_coinbase(address.encode().unwrap(), String::from("reward")).unwrap();
+        let block = Block::new_block(vec![tx("a"), tx("b"), coinbase], String::new(), 1).unwrap();
+        let compact = CompactBlock::new(&block);
+        assert_eq!(compact.short_ids.len(), 2);
+
+        let mempool = HashMap::from([(String::from("a"), tx("a")), (String::from("c"), tx("c"))]);
+        let mut partial = PartialBlock::new(compact.clone(), &mempool);
+        assert_eq!(partial.missing(), vec![1]);
+        assert!(partial.to_block().is_err());
+        partial.fill(vec![tx("b")]).unwrap();
+        assert_eq!(partial.to_block().unwrap().get_hash(), block.get_hash());
+
+        // a wrong transaction does not hash to the header
+        let mut partial = PartialBlock::new(compact, &mempool);
+        partial.fill(vec![tx("c")]).unwrap();
+        assert!(partial.to_block().is_err());
+    }
 }
@@ -5,18 +5,23 @@ use crate::block::*;
 use crate::transaction::*;
 use bincode::{deserialize, serialize};
 use failure::format_err;
-use sled;
 use std::collections::HashMap;
 use log::{debug, info};
 
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+/// BLOCKS_VERSION is the format of the stored blocks, bumped when a serialized
+/// block or transaction changes
+const BLOCKS_VERSION: u32 = 2;
+const VERSION_KEY: &str = "VERSION";
 
 /// Blockchain implements interactions with a DB
 #[derive(Debug)]
 pub struct Blockchain {
     pub tip: String,
     pub db: sled::Db,
+    /// coinbase_maturity is how many blocks a coinbase output waits before it can be spent
+    pub coinbase_maturity: i32,
 }
 
 /// BlockchainIterator is used to iterate over blockchain blocks
@@ -41,7 +46,35 @@ impl Blockchain {
         } else {
             String::from_utf8(hash.to_vec())?
         };
-        Ok(Blockchain { tip: lasthash, db })
+        let bc = Blockchain {
+            tip: lasthash,
+            db,
+            coinbase_maturity: COINBASE_MATURITY,
+        };
+        bc.check_version()?;
+        Ok(bc)
+    }
+
+    /// check_version refuses blocks written in an older format. They can not be
+    /// converted as their hashes would change, the chain has to be created or
+    /// downloaded again. A database without a version whose tip still reads is
+    /// in the current format and gets stamped
+    fn check_version(&self) -> Result<()> {
+        let version = match self.db.get(VERSION_KEY)? {
+            Some(v) => Some(deserialize::<u32>(&v)?),
+            None => None,
+        };
+        if version == Some(BLOCKS_VERSION) {
+            return Ok(());
+        }
+        if version.is_none() && (self.tip.is_empty() || self.get_block(&self.tip).is_ok()) {
+            self.db.insert(VERSION_KEY, serialize(&BLOCKS_VERSION)?)?;
+            return Ok(());
+        }
+        Err(format_err!(
+            "data/blocks was written by an older version and can not be read, \
+             remove the data directory and create or download the chain again"
+        ))
     }
 
     /// CreateBlockchain creates a new blockchain DB
@@ -55,9 +88,11 @@ impl Blockchain {
         let genesis: Block = Block::new_genesis_block(cbtx);
         db.insert(genesis.get_hash(), serialize(&genesis)?)?;
         db.insert("LAST", genesis.get_hash().as_bytes())?;
+        db.insert(VERSION_KEY, serialize(&BLOCKS_VERSION)?)?;
         let bc = Blockchain {
             tip: genesis.get_hash(),
             db,
+            coinbase_maturity: COINBASE_MATURITY,
         };
         bc.db.flush()?;
         Ok(bc)
@@ -67,19 +102,18 @@ impl Blockchain {
     pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
         info!("mine a new block");
 
+        let height = self.get_best_height()? + 1;
+        let lasthash = String::from_utf8(self.db.get("LAST")?.unwrap().to_vec())?;
         for tx in &transactions {
             if !self.verify_transacton(tx)? {
                 return Err(format_err!("ERROR: Invalid transaction"));
             }
+            if !self.verify_coinbase_maturity(tx, height, &lasthash)? {
+                return Err(format_err!("ERROR: Transaction spends an immature coinbase"));
+            }
         }
 
-        let lasthash = self.db.get("LAST")?.unwrap();
-
-        let newblock = Block::new_block(
-            transactions,
-            String::from_utf8(lasthash.to_vec())?,
-            self.get_best_height()? + 1,
-        )?;
+        let newblock = Block::new_block(transactions, lasthash, height)?;
         self.db.insert(newblock.get_hash(), serialize(&newblock)?)?;
         self.db.insert("LAST", newblock.get_hash().as_bytes())?;
         self.db.flush()?;
@@ -89,20 +123,27 @@ impl Blockchain {
     }
 
     /// Iterator returns a BlockchainIterat
-    pub fn iter(&self) -> BlockchainIterator {
+    pub fn iter(&self) -> BlockchainIterator<'_> {
+        self.iter_from(&self.tip)
+    }
+
+    /// IterFrom returns a BlockchainIterator walking back from the block hash
+    pub fn iter_from(&self, hash: &str) -> BlockchainIterator<'_> {
         BlockchainIterator {
-            current_hash: self.tip.clone(),
-            bc: &self,
+            current_hash: hash.to_string(),
+            bc: self,
         }
     }
 
     /// FindUTXO finds and returns all unspent transaction outputs
-    pub fn find_UTXO(&self) -> HashMap<String, TXOutputs> {
+    pub fn find_utxo(&self) -> HashMap<String, TXOutputs> {
         let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
         let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
 
         for block in self.iter() {
-            for tx in block.get_transaction() {
+            // children come after their parents in a block, walk backwards so
+            // that spent outputs are known before the transaction creating them
+            for tx in block.get_transaction().iter().rev() {
                 for index in 0..tx.vout.len() {
                     if let Some(ids) = spend_txos.get(&tx.id) {
                         if ids.contains(&(index as i32)) {
@@ -113,12 +154,16 @@ impl Blockchain {
                     match utxos.get_mut(&tx.id) {
                         Some(v) => {
                             v.outputs.push(tx.vout[index].clone());
+                            v.indexes.push(index as i32);
                         }
                         None => {
                             utxos.insert(
                                 tx.id.clone(),
                                 TXOutputs {
                                     outputs: vec![tx.vout[index].clone()],
+                                    indexes: vec![index as i32],
+                                    height: block.get_height(),
+                                    coinbase: tx.is_coinbase(),
                                 },
                             );
                         }
@@ -155,19 +200,19 @@ impl Blockchain {
         Err(format_err!("Transaction is not found"))
     }
 
-    fn get_prev_TXs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
-        let mut prev_TXs = HashMap::new();
+    fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+        let mut prev_txs = HashMap::new();
         for vin in &tx.vin {
-            let prev_TX = self.find_transacton(&vin.txid)?;
-            prev_TXs.insert(prev_TX.id.clone(), prev_TX);
+            let prev_tx = self.find_transacton(&vin.txid)?;
+            prev_txs.insert(prev_tx.id.clone(), prev_tx);
         }
-        Ok(prev_TXs)
+        Ok(prev_txs)
     }
 
     /// SignTransaction signs inputs of a Transaction
     pub fn sign_transacton(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
-        let prev_TXs = self.get_prev_TXs(tx)?;
-        tx.sign(private_key, prev_TXs)?;
+        let prev_txs = self.get_prev_txs(tx)?;
+        tx.sign(private_key, prev_txs)?;
         Ok(())
     }
 
@@ -176,16 +221,47 @@ impl Blockchain {
         if tx.is_coinbase() {
             return Ok(true);
         }
-        let prev_TXs = self.get_prev_TXs(tx)?;
-        tx.verify(prev_TXs)
+        let prev_txs = self.get_prev_txs(tx)?;
+        tx.verify(prev_txs)
     }
 
-    /// AddBlock saves the block into the blockchain
+    /// VerifyCoinbaseMaturity checks that tx, mined at height on top of the block
+    /// prev_hash, only spends coinbase outputs at least coinbase_maturity blocks older
+    pub fn verify_coinbase_maturity(
+        &self,
+        tx: &Transaction,
+        height: i32,
+        prev_hash: &str,
+    ) -> Result<bool> {
+        if tx.is_coinbase() {
+            return Ok(true);
+        }
+        for block in self.iter_from(prev_hash) {
+            if height - block.get_height() >= self.coinbase_maturity {
+                break;
+            }
+            for prev in block.get_transaction() {
+                if prev.is_coinbase() && tx.vin.iter().any(|vin| vin.txid == prev.id) {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// AddBlock saves the block into the blockchain, its parent must be stored
+    /// already
     pub fn add_block(&mut self, block: Block) -> Result<()> {
         let data = serialize(&block)?;
-        if let Some(_) = self.db.get(block.get_hash())? {
+        if self.db.get(block.get_hash())?.is_some() {
             return Ok(());
         }
+        self.check_parent(&block)?;
+        for tx in block.get_transaction() {
+            if !self.verify_coinbase_maturity(tx, block.get_height(), &block.get_prev_hash())? {
+                return Err(format_err!("block {} spends an immature coinbase", block.get_hash()));
+            }
+        }
         self.db.insert(block.get_hash(), data)?;
 
         let lastheight = self.get_best_height()?;
@@ -197,10 +273,39 @@ impl Blockchain {
         Ok(())
     }
 
+    /// check_parent rejects an orphan block, whose parent is not stored, and a
+    /// block whose height does not follow its parent's. Only an empty chain
+    /// takes a genesis block
+    fn check_parent(&self, block: &Block) -> Result<()> {
+        let prev_hash = block.get_prev_hash();
+        if prev_hash.is_empty() {
+            if !self.tip.is_empty() || block.get_height() != 0 {
+                return Err(format_err!("unexpected genesis block {}", block.get_hash()));
+            }
+            return Ok(());
+        }
+        let parent = match self.db.get(&prev_hash)? {
+            Some(data) => deserialize::<Block>(&data)?,
+            None => return Err(format_err!("orphan block {}, parent {} is unknown", block.get_hash(), prev_hash)),
+        };
+        if block.get_height() != parent.get_height() + 1 {
+            return Err(format_err!("block {} height does not follow its parent", block.get_hash()));
+        }
+        Ok(())
+    }
+
+    /// HasBlock reports whether the block is stored already
+    pub fn has_block(&self, block_hash: &str) -> Result<bool> {
+        Ok(self.db.contains_key(block_hash)?)
+    }
+
     // GetBlock finds a block by its hash and returns it
     pub fn get_block(&self, block_hash: &str) -> Result<Block> {
-        let data = self.db.get(block_hash)?.unwrap();
-        let block = deserialize(&data.to_vec())?;
+        let data = match self.db.get(block_hash)? {
+            Some(data) => data,
+            None => return Err(format_err!("Block is not found")),
+        };
+        let block = deserialize(&data)?;
         Ok(block)
     }
 
@@ -212,7 +317,7 @@ impl Blockchain {
             return Ok(-1);
         };
         let last_data = self.db.get(lasthash)?.unwrap();
-        let last_block: Block = deserialize(&last_data.to_vec())?;
+        let last_block: Block = deserialize(&last_data)?;
         Ok(last_block.get_height())
     }
 
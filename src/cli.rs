@@ -1,12 +1,28 @@
 use std::process::exit;
 use bitcoincash_addr::Address;
-use clap::{arg, Command};
+use clap::{arg, ArgAction, ArgMatches, Command};
+use crate::block::{available_threads, Block};
 use crate::blockchain::Blockchain;
+use crate::coinselect::{self, DEFAULT_STRATEGY};
+use crate::config::{Config, DEFAULT_CONFIG_FILE};
 use crate::error::Result;
+use crate::ledger::{self, Ledger, WalletTx};
+use crate::psbt::Psbt;
+use crate::rpc;
 use crate::server::Server;
-use crate::transaction::Transaction;
+use crate::transaction::{TXOutput, Transaction};
 use crate::utxoset::UTXOSet;
-use crate::wallet:: Wallets;
+use crate::wallet::{address_from_pub_key_hash, Wallets, DEFAULT_GAP_LIMIT};
+use failure::format_err;
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 pub struct Cli {}
 
@@ -19,19 +35,97 @@ impl Cli {
             .version("0.1")
             .author("behrouz.r.fa@gmail.com")
             .about("blockchain in rust: a simple blockchain for learning")
-            .subcommand(Command::new("printchain").about("print all the chain blocks"))
-            .subcommand(Command::new("createwallet").about("create a wallet"))
+            .subcommand(Command::new("printchain")
+                .about("print all the chain blocks")
+                .args(rpc_args())
+            )
+            .subcommand(Command::new("createwallet")
+                .about("create a wallet")
+                .arg(arg!(--mnemonic " 'start deriving addresses from a new seed and print its mnemonic'"))
+                .args(rpc_args())
+            )
+            .subcommand(Command::new("restorewallet")
+                .about("restore the addresses derived from a mnemonic")
+                .arg(arg!(<MNEMONIC>... " 'the mnemonic words'"))
+                .arg(arg!(--gaplimit <N> " 'unused addresses in a row that end the scan, 20 by default'"))
+                .args(rpc_args())
+            )
             .subcommand(Command::new("listaddresses").about("list all addresses"))
-            .subcommand(Command::new("reindex").about("reindex UTXO"))
+            .subcommand(Command::new("listtransactions")
+                .about("list the most recent wallet transactions")
+                .arg(arg!(--count <N> " 'how many transactions to list'").default_value("10"))
+                .arg(arg!(--skip <N> " 'how many of the most recent transactions to skip'").default_value("0"))
+                .args(rpc_args())
+            )
+            .subcommand(Command::new("gettransaction")
+                .about("show a wallet transaction")
+                .arg(arg!(<TXID>" 'the transaction id'"))
+                .args(rpc_args())
+            )
+            .subcommand(Command::new("dumpprivkey")
+                .about("print the private key of an address")
+                .arg(arg!(<ADDRESS>" 'the wallet address'"))
+            )
+            .subcommand(Command::new("importprivkey")
+                .about("add a private key printed by dumpprivkey")
+                .arg(arg!(<KEY>" 'the private key as hex'"))
+            )
+            .subcommand(Command::new("importaddress")
+                .about("watch an address without its private key")
+                .arg(arg!(<ADDRESS>" 'the address to watch'"))
+            )
+            .subcommand(Command::new("dumpwallet")
+                .about("write every key and watched address to a file")
+                .arg(arg!(<FILE>" 'the dump file to create'"))
+            )
+            .subcommand(Command::new("importwallet")
+                .about("import the keys and addresses of a wallet dump")
+                .arg(arg!(<FILE>" 'the dump file to read'"))
+            )
+            .subcommand(Command::new("encryptwallet")
+                .about("encrypt the wallet keys with a passphrase")
+                .arg(arg!(<PASSPHRASE>" 'the passphrase protecting the keys'"))
+            )
+            .subcommand(Command::new("walletpassphrase")
+                .about("unlock the wallet keys for a while, in the memory of the running node")
+                .arg(arg!(<PASSPHRASE>" 'the wallet passphrase'"))
+                .arg(arg!(<TIMEOUT>" 'seconds the wallet stays unlocked'"))
+                .args(rpc_args())
+            )
+            .subcommand(Command::new("walletlock")
+                .about("lock the wallet keys, wiping them from the running node")
+                .args(rpc_args())
+            )
+            .subcommand(Command::new("reindex")
+                .about("reindex UTXO")
+                .args(rpc_args())
+            )
+            .subcommand(Command::new("benchpow")
+                .about("measure the proof of work hashrate")
+                .arg(arg!(--threads <N> " 'number of mining threads'"))
+                .arg(arg!(--seconds <N> " 'how long to run'").default_value("10"))
+            )
             .subcommand(Command::new("getbalance")
                 .about("get balance in the blochain")
-                .arg(arg!(<ADDRESS>"'The Address it get balance for'"))
+                .arg(arg!([ADDRESS]"'The Address it get balance for, every wallet address when omitted'"))
+                .arg(arg!(--minconf <N> " 'confirmations a wallet output needs to count as confirmed'").default_value("1"))
+                .arg(arg!(--devnet " 'test network rules, coinbase outputs mature after one block'"))
+                .args(rpc_args())
+            )
+            .subcommand(Command::new("rpc")
+                .about("call a method on the running node")
+                .arg(arg!(<METHOD>" 'RPC method name'"))
+                .arg(arg!([PARAMS]... " 'method parameters, JSON or plain strings'"))
+                .args(rpc_args())
             ).subcommand(Command::new("startnode")
             .about("start the node server")
             .arg(arg!(<PORT>"'the port server bind to locally'"))
+            .args(network_args())
+            .args(listen_args())
         )
             .subcommand(Command::new("create").about("Create new blochain")
                 .arg(arg!(<ADDRESS>"'The address to send gensis block reqward to' "))
+                .args(rpc_args())
             )
 
             .subcommand(
@@ -40,18 +134,110 @@ impl Cli {
                     .arg(arg!(<FROM>" 'Source wallet address'"))
                     .arg(arg!(<TO>" 'Destination wallet address'"))
                     .arg(arg!(<AMOUNT>" 'Destination wallet address'"))
-                    .arg(arg!(-m --mine " 'the from address mine immediately'")),
+                    .arg(arg!(-m --mine " 'the from address mine immediately'"))
+                    .args(network_args()),
+            )
+            .subcommand(
+                Command::new("sendtoaddress")
+                    .about("send from any wallet address, the change goes to a new address")
+                    .arg(arg!(<TO>" 'Destination wallet address'"))
+                    .arg(arg!(<AMOUNT>" 'amount to send'"))
+                    .arg(arg!(--coinselect <STRATEGY> " 'largest, bnb or privacy, bnb by default'"))
+                    .arg(arg!(-m --mine " 'mine the transaction immediately'"))
+                    .args(network_args()),
+            )
+            .subcommand(
+                Command::new("sendmany")
+                    .about("pay many addresses in one transaction from any wallet address")
+                    .arg(arg!(<RECIPIENTS>" 'JSON or CSV address/amount list, or a file holding it'"))
+                    .arg(arg!(--coinselect <STRATEGY> " 'largest, bnb or privacy, bnb by default'"))
+                    .arg(arg!(-m --mine " 'mine the transaction immediately'"))
+                    .args(network_args()),
+            )
+            .subcommand(
+                Command::new("bumpfee")
+                    .about("replace a wallet transaction waiting in the mempool by one paying a higher fee")
+                    .arg(arg!(<TXID>" 'the transaction to replace'"))
+                    .arg(arg!(--feerate <N> " 'fee per 1000 bytes of the replacement'"))
+                    .args(rpc_args()),
+            )
+            .subcommand(
+                Command::new("createrawtransaction")
+                    .about("create an unsigned transaction and print it as hex")
+                    .arg(arg!(--input <TXID_VOUT> " 'output to spend as TXID:VOUT, may be repeated'")
+                        .required(true)
+                        .action(ArgAction::Append))
+                    .arg(arg!(--output <ADDRESS_AMOUNT> " 'payment as ADDRESS:AMOUNT, may be repeated'")
+                        .required(true)
+                        .action(ArgAction::Append)),
+            )
+            .subcommand(
+                Command::new("signrawtransaction")
+                    .about("sign the inputs of a hex transaction owned by the local wallets")
+                    .arg(arg!(<HEX>" 'the transaction to sign'"))
+                    .arg(arg!(--prevout <TXID_VOUT_ADDRESS> " 'output spent by an input, for offline signing, may be repeated'")
+                        .action(ArgAction::Append))
+                    .args(rpc_args()),
+            )
+            .subcommand(
+                Command::new("decoderawtransaction")
+                    .about("print a hex transaction as JSON")
+                    .arg(arg!(<HEX>" 'the transaction to decode'")),
+            )
+            .subcommand(
+                Command::new("sendrawtransaction")
+                    .about("broadcast a signed hex transaction")
+                    .arg(arg!(<HEX>" 'the transaction to send'"))
+                    .args(network_args())
+                    .arg(arg!(--rpcconnect <ADDR> " 'address of the node JSON-RPC endpoint'")),
+            )
+            .subcommand(
+                Command::new("createpsbt")
+                    .about("create a partially signed transaction for the signers")
+                    .arg(arg!(--input <TXID_VOUT> " 'output to spend as TXID:VOUT, may be repeated'")
+                        .required(true)
+                        .action(ArgAction::Append))
+                    .arg(arg!(--output <ADDRESS_AMOUNT> " 'payment as ADDRESS:AMOUNT, may be repeated'")
+                        .required(true)
+                        .action(ArgAction::Append))
+                    .arg(arg!(--out <FILE> " 'write the PSBT to this file'"))
+                    .args(rpc_args()),
+            )
+            .subcommand(
+                Command::new("walletprocesspsbt")
+                    .about("add the local wallets signatures to a PSBT")
+                    .arg(arg!(<PSBT>" 'PSBT file or base64 string'"))
+                    .arg(arg!(--out <FILE> " 'write the PSBT to this file'")),
+            )
+            .subcommand(
+                Command::new("combinepsbt")
+                    .about("merge the signatures of several PSBTs of the same transaction")
+                    .arg(arg!(<PSBT>... " 'PSBT files or base64 strings'"))
+                    .arg(arg!(--out <FILE> " 'write the PSBT to this file'")),
+            )
+            .subcommand(
+                Command::new("finalizepsbt")
+                    .about("finalize a PSBT and print the signed transaction hex once complete")
+                    .arg(arg!(<PSBT>" 'PSBT file or base64 string'"))
+                    .arg(arg!(--out <FILE> " 'write the PSBT to this file when incomplete'")),
+            )
+            .subcommand(
+                Command::new("decodepsbt")
+                    .about("print a PSBT as JSON")
+                    .arg(arg!(<PSBT>" 'PSBT file or base64 string'")),
             )
             .subcommand(
                 Command::new("startminer")
                     .about("start the minner server")
                     .arg(arg!(<PORT>" 'the port server bind to locally'"))
-                    .arg(arg!(<ADDRESS>" 'wallet address'")),
+                    .arg(arg!(<ADDRESS>" 'wallet address'"))
+                    .args(network_args())
+                    .args(listen_args()),
 
             )
             .get_matches();
 
-        if let Some(ref matches) = matches.subcommand_matches("startminer") {
+        if let Some(matches) = matches.subcommand_matches("startminer") {
             let port = if let Some(port) = matches.get_one::<String>("PORT") {
                 port
             } else {
@@ -65,50 +251,184 @@ impl Cli {
                 println!("ADDRESS not supply!: usage");
                 exit(1)
             };
+            let config = load_config(matches)?;
             let bc = Blockchain::new()?;
-            let utxo_set = UTXOSet { blockchain: bc };
-            let server = Server::new(port, address, utxo_set)?;
+            let utxo_set = UTXOSet::new(bc)?;
+            let server = Server::new(port, address, utxo_set, &config)?;
             server.start_server()?;
         }
 
 
-        if let Some(ref matches) = matches.subcommand_matches("startnode") {
+        if let Some(matches) = matches.subcommand_matches("startnode") {
             if let Some(port) = matches.get_one::<String>("PORT") {
+                let config = load_config(matches)?;
                 let bc = Blockchain::new()?;
-                let utxo_set = UTXOSet { blockchain: bc };
-                let server = Server::new(port, "", utxo_set)?;
+                let utxo_set = UTXOSet::new(bc)?;
+                let server = Server::new(port, "", utxo_set, &config)?;
                 server.start_server()?;
             }
         }
 
-        if let Some(_) = matches.subcommand_matches("createwallet") {
-            println!("address: {}", cmd_create_wallet()?);
+        if let Some(matches) = matches.subcommand_matches("createwallet") {
+            if matches.get_flag("mnemonic") {
+                let (mnemonic, address) = cmd_create_hd_wallet()?;
+                println!("mnemonic: {}", mnemonic);
+                println!("write the mnemonic down, it restores every address of this wallet");
+                println!("address: {}", address);
+            } else {
+                println!("address: {}", cmd_create_wallet(&load_config(matches)?)?);
+            }
         }
-        if let Some(_) = matches.subcommand_matches("reindex") {
+        if let Some(matches) = matches.subcommand_matches("restorewallet") {
+            let words: Vec<&str> = matches.get_many::<String>("MNEMONIC").unwrap().map(|w| w.as_str()).collect();
+            let gap_limit: u32 = match matches.get_one::<String>("gaplimit") {
+                Some(n) => n.parse()?,
+                None => DEFAULT_GAP_LIMIT,
+            };
+            let addresses = cmd_restore_wallet(&words.join(" "), gap_limit, &load_config(matches)?)?;
+            println!("restored {} addresses:", addresses.len());
+            for address in addresses {
+                println!("{}", address);
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("listtransactions") {
+            let config = load_config(matches)?;
+            let count: usize = matches.get_one::<String>("count").unwrap().parse()?;
+            let skip: usize = matches.get_one::<String>("skip").unwrap().parse()?;
+            let ledger = sync_ledger(&config)?;
+            let txs = ledger.transactions();
+            let end = txs.len() - skip.min(txs.len());
+            let list: Vec<Value> = txs[end.saturating_sub(count)..end]
+                .iter()
+                .map(|wtx| wallet_tx_to_json(wtx, ledger.confirmations(wtx)))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&list)?);
+        }
+        if let Some(matches) = matches.subcommand_matches("gettransaction") {
+            let config = load_config(matches)?;
+            let txid = matches.get_one::<String>("TXID").unwrap();
+            let ledger = sync_ledger(&config)?;
+            let wtx = match ledger.get(txid) {
+                Some(wtx) => wtx,
+                None => return Err(format_err!("{} is not a wallet transaction", txid)),
+            };
+            let mut result = wallet_tx_to_json(wtx, ledger.confirmations(wtx));
+            result["details"] = json!(wtx.received);
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        }
+        if let Some(matches) = matches.subcommand_matches("dumpprivkey") {
+            let ws = Wallets::new()?;
+            println!("{}", ws.dump_private_key(matches.get_one::<String>("ADDRESS").unwrap())?);
+        }
+        if let Some(matches) = matches.subcommand_matches("importprivkey") {
+            let mut ws = Wallets::new()?;
+            let address = ws.import_private_key(matches.get_one::<String>("KEY").unwrap())?;
+            ws.save_all()?;
+            println!("address: {}", address);
+        }
+        if let Some(matches) = matches.subcommand_matches("importaddress") {
+            let mut ws = Wallets::new()?;
+            ws.import_address(matches.get_one::<String>("ADDRESS").unwrap())?;
+            ws.save_all()?;
+            println!("watching: {}", matches.get_one::<String>("ADDRESS").unwrap());
+        }
+        if let Some(matches) = matches.subcommand_matches("dumpwallet") {
+            let path = matches.get_one::<String>("FILE").unwrap();
+            cmd_dump_wallet(path)?;
+            println!("wallet dumped to {}", path);
+        }
+        if let Some(matches) = matches.subcommand_matches("importwallet") {
+            let mut ws = Wallets::new()?;
+            let imported = ws.import_dump(&std::fs::read_to_string(matches.get_one::<String>("FILE").unwrap())?)?;
+            ws.save_all()?;
+            println!("imported {} entries", imported);
+        }
+        if let Some(matches) = matches.subcommand_matches("encryptwallet") {
+            let mut ws = Wallets::new()?;
+            ws.encrypt_wallet(matches.get_one::<String>("PASSPHRASE").unwrap())?;
+            println!("wallet encrypted, unlock it with walletpassphrase before sending");
+        }
+        if let Some(matches) = matches.subcommand_matches("walletpassphrase") {
+            let config = load_config(matches)?;
+            let timeout: u64 = matches.get_one::<String>("TIMEOUT").unwrap().parse()?;
+            if !rpc::is_node_running(&config.rpc_address) {
+                return Err(format_err!("walletpassphrase needs a running node, the key is only kept in its memory"));
+            }
+            let passphrase = matches.get_one::<String>("PASSPHRASE").unwrap();
+            rpc::call(&config, "walletpassphrase", vec![json!(passphrase), json!(timeout)])?;
+            println!("wallet unlocked for {}s", timeout);
+        }
+        if let Some(matches) = matches.subcommand_matches("walletlock") {
+            let config = load_config(matches)?;
+            if !rpc::is_node_running(&config.rpc_address) {
+                return Err(format_err!("walletlock needs a running node"));
+            }
+            rpc::call(&config, "walletlock", vec![])?;
+            println!("wallet locked");
+        }
+        if let Some(matches) = matches.subcommand_matches("reindex") {
+            ensure_no_node("reindex", &load_config(matches)?)?;
             let count = cmd_reindex()?;
             println!("Done! There are {} transactions in the UTXO set.", count);
         }
 
-        if let Some(_) = matches.subcommand_matches("listaddresses") {
+        if let Some(matches) = matches.subcommand_matches("benchpow") {
+            let threads: usize = match matches.get_one::<String>("threads") {
+                Some(n) => n.parse()?,
+                None => available_threads(),
+            };
+            let seconds: u64 = matches.get_one::<String>("seconds").unwrap().parse()?;
+            cmd_bench_pow(threads, seconds)?;
+        }
+
+        if matches.subcommand_matches("listaddresses").is_some() {
             cmd_list_address()?;
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("create") {
+        if let Some(matches) = matches.subcommand_matches("create") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                ensure_no_node("create", &load_config(matches)?)?;
                 cmd_create_blockchain(address)?;
             }
 
         }
 
 
-        if let Some(ref matches) = matches.subcommand_matches("getbalance") {
+        if let Some(matches) = matches.subcommand_matches("getbalance") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let balance = cmd_get_balance(address)?;
+                let config = load_config(matches)?;
+                let balance = if rpc::is_node_running(&config.rpc_address) {
+                    rpc::call(&config, "getbalance", vec![Value::from(address.as_str())])?
+                        .as_i64()
+                        .unwrap_or(0) as i32
+                } else {
+                    cmd_get_balance(address)?
+                };
                 println!("Balance: {}\n", balance);
+            } else {
+                let config = load_config(matches)?;
+                let minconf: i32 = matches.get_one::<String>("minconf").unwrap().parse()?;
+                let balance = sync_ledger(&config)?.balance(minconf, config.coinbase_maturity);
+                println!("Confirmed: {}", balance.confirmed);
+                println!("Unconfirmed: {}", balance.unconfirmed);
+                println!("Immature: {}\n", balance.immature);
             }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("send") {
+        if let Some(matches) = matches.subcommand_matches("rpc") {
+            let config = load_config(matches)?;
+            let method = matches.get_one::<String>("METHOD").unwrap();
+            let params: Vec<Value> = match matches.get_many::<String>("PARAMS") {
+                Some(params) => params
+                    .map(|p| serde_json::from_str(p).unwrap_or_else(|_| Value::from(p.as_str())))
+                    .collect(),
+                None => Vec::new(),
+            };
+            let result = rpc::call(&config, method, params)?;
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("send") {
             let from = if let Some(address) = matches.get_one::<String>("FROM") {
                 address
             } else {
@@ -130,10 +450,11 @@ impl Cli {
                 exit(1)
             };
 
-            if matches.contains_id("mine") {
-                cmd_send(from, to, amount, true)?;
+            let config = load_config(matches)?;
+            if matches.get_flag("mine") {
+                cmd_send(from, to, amount, true, &config)?;
             } else {
-                cmd_send(from, to, amount, false)?;
+                cmd_send(from, to, amount, false, &config)?;
             }
 
 
@@ -142,40 +463,587 @@ impl Cli {
             }*/
         }
 
-        if let Some(_) = matches.subcommand_matches("printchain") {
-            cmd_print_chain()?;
+        if let Some(matches) = matches.subcommand_matches("sendtoaddress") {
+            let config = load_config(matches)?;
+            let to = matches.get_one::<String>("TO").unwrap();
+            let amount: i32 = matches.get_one::<String>("AMOUNT").unwrap().parse()?;
+            let strategy = match matches.get_one::<String>("coinselect") {
+                Some(name) => name.as_str(),
+                None => DEFAULT_STRATEGY,
+            };
+            let txid = cmd_send_many(&[(to.clone(), amount)], strategy, matches.get_flag("mine"), &config)?;
+            println!("txid: {}", txid);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sendmany") {
+            let config = load_config(matches)?;
+            let arg = matches.get_one::<String>("RECIPIENTS").unwrap();
+            let data = if Path::new(arg).is_file() {
+                std::fs::read_to_string(arg)?
+            } else {
+                arg.clone()
+            };
+            let outputs = parse_recipients(&data)?;
+            let strategy = match matches.get_one::<String>("coinselect") {
+                Some(name) => name.as_str(),
+                None => DEFAULT_STRATEGY,
+            };
+            let txid = cmd_send_many(&outputs, strategy, matches.get_flag("mine"), &config)?;
+            println!("paid {} addresses, txid: {}", outputs.len(), txid);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("bumpfee") {
+            let config = load_config(matches)?;
+            let fee_rate = match matches.get_one::<String>("feerate") {
+                Some(rate) => Some(rate.parse()?),
+                None => None,
+            };
+            let (txid, old_fee, new_fee) = cmd_bump_fee(matches.get_one::<String>("TXID").unwrap(), fee_rate, &config)?;
+            println!("txid: {}", txid);
+            println!("fee: {} -> {}", old_fee, new_fee);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createrawtransaction") {
+            let inputs = matches
+                .get_many::<String>("input")
+                .unwrap()
+                .map(|i| parse_outpoint(i))
+                .collect::<Result<Vec<_>>>()?;
+            let outputs = matches
+                .get_many::<String>("output")
+                .unwrap()
+                .map(|o| parse_payment(o))
+                .collect::<Result<Vec<_>>>()?;
+            let tx = Transaction::new_raw(&inputs, &outputs)?;
+            println!("{}", tx.to_hex()?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("signrawtransaction") {
+            let config = load_config(matches)?;
+            let hex = matches.get_one::<String>("HEX").unwrap();
+            let prevouts: Vec<String> = match matches.get_many::<String>("prevout") {
+                Some(p) => p.cloned().collect(),
+                None => Vec::new(),
+            };
+            let (hex, complete) = cmd_sign_raw_transaction(hex, &prevouts, &config)?;
+            println!("{}", hex);
+            println!("complete: {}", complete);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("decoderawtransaction") {
+            let tx = Transaction::from_hex(matches.get_one::<String>("HEX").unwrap())?;
+            println!("{}", serde_json::to_string_pretty(&rpc::tx_to_json(&tx))?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sendrawtransaction") {
+            let config = load_config(matches)?;
+            let tx = Transaction::from_hex(matches.get_one::<String>("HEX").unwrap())?;
+            if rpc::is_node_running(&config.rpc_address) {
+                rpc::call(&config, "sendrawtransaction", vec![Value::from(tx.to_hex()?)])?;
+            } else {
+                let bc = Blockchain::new()?;
+                let utxo_set = UTXOSet::new(bc)?;
+                Server::send_transaction(&tx, utxo_set, &config)?;
+            }
+            println!("txid: {}", tx.id);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createpsbt") {
+            let config = load_config(matches)?;
+            let inputs = matches
+                .get_many::<String>("input")
+                .unwrap()
+                .map(|i| parse_outpoint(i))
+                .collect::<Result<Vec<_>>>()?;
+            let outputs = matches
+                .get_many::<String>("output")
+                .unwrap()
+                .map(|o| parse_payment(o))
+                .collect::<Result<Vec<_>>>()?;
+            let mut psbt = Psbt::new(&Transaction::new_raw(&inputs, &outputs)?)?;
+            psbt.update(&fetch_prevouts(&inputs, &config));
+            write_psbt(&psbt, matches.get_one::<String>("out"))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("walletprocesspsbt") {
+            let mut psbt = read_psbt(matches.get_one::<String>("PSBT").unwrap())?;
+            let signed = psbt.sign(&Wallets::new()?)?;
+            eprintln!("signed {} inputs", signed);
+            write_psbt(&psbt, matches.get_one::<String>("out"))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("combinepsbt") {
+            let mut psbts = matches.get_many::<String>("PSBT").unwrap();
+            let mut psbt = read_psbt(psbts.next().unwrap())?;
+            for other in psbts {
+                psbt.combine(&read_psbt(other)?)?;
+            }
+            write_psbt(&psbt, matches.get_one::<String>("out"))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("finalizepsbt") {
+            let mut psbt = read_psbt(matches.get_one::<String>("PSBT").unwrap())?;
+            if psbt.finalize()? {
+                println!("{}", psbt.extract()?.to_hex()?);
+                println!("complete: true");
+            } else {
+                write_psbt(&psbt, matches.get_one::<String>("out"))?;
+                println!("complete: false");
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("decodepsbt") {
+            let psbt = read_psbt(matches.get_one::<String>("PSBT").unwrap())?;
+            println!("{}", serde_json::to_string_pretty(&psbt_to_json(&psbt))?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("printchain") {
+            cmd_print_chain(&load_config(matches)?)?;
         }
 
         Ok(())
     }
 }
 
-fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
-    let bc = Blockchain::new()?;
-    let mut utxo_set = UTXOSet { blockchain: bc };
+/// network_args are the peer options shared by the commands that talk to the network
+fn network_args() -> Vec<clap::Arg> {
+    vec![
+        arg!(--config <FILE> " 'the config file to read'").default_value(DEFAULT_CONFIG_FILE),
+        arg!(--connect <ADDR> " 'connect only to this node, may be repeated'")
+            .action(ArgAction::Append),
+        arg!(--addnode <ADDR> " 'add a node to connect to, may be repeated'")
+            .action(ArgAction::Append),
+        arg!(--devnet " 'test network rules, coinbase outputs mature after one block'"),
+    ]
+}
+
+/// listen_args are the options of the commands that accept connections
+fn listen_args() -> Vec<clap::Arg> {
+    vec![
+        arg!(--bind <ADDR> " 'local IPv4 or IPv6 address to listen on instead of localhost, 0.0.0.0 or :: for all interfaces, may be repeated'")
+            .action(ArgAction::Append),
+        arg!(--externalip <ADDR> " 'address advertised to other nodes'"),
+        arg!(--rpcbind <ADDR> " 'address the JSON-RPC endpoint listens on'"),
+        arg!(--norpc " 'do not start the JSON-RPC endpoint'"),
+    ]
+}
+
+/// rpc_args are the options of the commands that can talk to a running node
+fn rpc_args() -> Vec<clap::Arg> {
+    vec![
+        arg!(--config <FILE> " 'the config file to read'").default_value(DEFAULT_CONFIG_FILE),
+        arg!(--rpcconnect <ADDR> " 'address of the node JSON-RPC endpoint'"),
+    ]
+}
+
+/// load_config reads the config file and applies the command line overrides
+fn load_config(matches: &ArgMatches) -> Result<Config> {
+    let path = matches.get_one::<String>("config").unwrap();
+    let mut config = Config::load(path)?;
+    if let Ok(Some(nodes)) = matches.try_get_many::<String>("connect") {
+        config.connect = nodes.cloned().collect();
+    }
+    if let Ok(Some(nodes)) = matches.try_get_many::<String>("addnode") {
+        config.addnode.extend(nodes.cloned());
+    }
+    if let Ok(Some(addrs)) = matches.try_get_many::<String>("bind") {
+        config.bind = addrs.cloned().collect();
+    }
+    if let Ok(Some(addr)) = matches.try_get_one::<String>("externalip") {
+        config.external_address = Some(addr.clone());
+    }
+    if let Ok(Some(addr)) = matches.try_get_one::<String>("rpcbind") {
+        config.rpc_address = addr.clone();
+    }
+    if let Ok(Some(addr)) = matches.try_get_one::<String>("rpcconnect") {
+        config.rpc_address = addr.clone();
+    }
+    if let Ok(Some(true)) = matches.try_get_one::<bool>("norpc") {
+        config.rpc_enabled = false;
+    }
+    if let Ok(Some(true)) = matches.try_get_one::<bool>("devnet") {
+        config.use_devnet();
+    }
+    Ok(config)
+}
+
+/// ensure_no_node fails when a node is running, since it holds the chain
+/// databases the command would open
+fn ensure_no_node(command: &str, config: &Config) -> Result<()> {
+    if rpc::is_node_running(&config.rpc_address) {
+        return Err(format_err!("{} opens the chain databases, stop the node first", command));
+    }
+    Ok(())
+}
+
+/// cmd_send pays amount to `to` from the from address. With a node running the
+/// node builds and signs the transaction, with the keys walletpassphrase unlocked
+fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool, config: &Config) -> Result<()> {
+    if rpc::is_node_running(&config.rpc_address) {
+        if mine_now {
+            return Err(format_err!("--mine mines a block locally, it can not be used while a node is running"));
+        }
+        rpc::call(config, "sendfrom", vec![json!(from), json!(to), json!(amount)])?;
+        println!("success!");
+        return Ok(());
+    }
+    let mut bc = Blockchain::new()?;
+    bc.coinbase_maturity = config.coinbase_maturity;
+    let mut utxo_set = UTXOSet::new(bc)?;
     let wallets = Wallets::new()?;
-    let wallet = wallets.get_wallet(from).unwrap();
-    let tx = Transaction::new_UTXO(wallet, to, amount, &utxo_set)?;
+    wallets.ensure_unlocked()?;
+    let wallet = match wallets.get_wallet(from) {
+        Some(w) => w,
+        None => return Err(format_err!("{} is not a wallet address", from)),
+    };
+    let fee_rate = if mine_now { 0 } else { config.min_relay_fee };
+    let tx = Transaction::new_utxo(wallet, to, amount, fee_rate, &utxo_set)?;
+    if tx.vout.len() > 1 {
+        wallets.record_change(&tx.id, 1)?;
+    }
     if mine_now {
         let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"))?;
         let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
 
         utxo_set.update(&new_block)?;
     } else {
-        Server::send_transaction(&tx, utxo_set)?;
+        Server::send_transaction(&tx, utxo_set, config)?;
     }
 
     println!("success!");
     Ok(())
 }
 
-fn cmd_create_wallet() -> Result<String> {
+/// cmd_send_many pays the (address, amount) outputs in one transaction from the
+/// coins of every wallet address picked by the strategy coin selection, returns
+/// the transaction id. With a node running the node builds and signs it
+fn cmd_send_many(outputs: &[(String, i32)], strategy: &str, mine_now: bool, config: &Config) -> Result<String> {
+    if rpc::is_node_running(&config.rpc_address) {
+        if mine_now {
+            return Err(format_err!("--mine mines a block locally, it can not be used while a node is running"));
+        }
+        let pairs: Vec<Value> = outputs.iter().map(|(address, amount)| json!([address, amount])).collect();
+        let txid = rpc::call(config, "sendmany", vec![json!(pairs), json!(strategy)])?;
+        return Ok(txid.as_str().unwrap_or_default().to_string());
+    }
+    let selector = coinselect::selector(strategy)?;
+    let mut bc = Blockchain::new()?;
+    bc.coinbase_maturity = config.coinbase_maturity;
+    let mut utxo_set = UTXOSet::new(bc)?;
+    let mut wallets = Wallets::new()?;
+    let fee_rate = if mine_now { 0 } else { config.min_relay_fee };
+    let tx = Transaction::new_wallet_send(&mut wallets, outputs, selector.as_ref(), fee_rate, &utxo_set)?;
+    let txid = tx.id.clone();
+    if mine_now {
+        let reward = wallets.create_wallet()?;
+        wallets.save_all()?;
+        let cbtx = Transaction::new_coinbase(reward, String::from("reward!"))?;
+        let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
+
+        utxo_set.update(&new_block)?;
+    } else {
+        Server::send_transaction(&tx, utxo_set, config)?;
+    }
+    Ok(txid)
+}
+
+/// cmd_bump_fee asks the running node to replace a wallet transaction of its
+/// mempool by one paying a higher fee, returns the new txid, the old and the new fee
+fn cmd_bump_fee(txid: &str, fee_rate: Option<i32>, config: &Config) -> Result<(String, i32, i32)> {
+    if !rpc::is_node_running(&config.rpc_address) {
+        return Err(format_err!("bumpfee needs a running node"));
+    }
+    let result = rpc::call(config, "bumpfee", vec![json!(txid), json!(fee_rate)])?;
+    Ok((
+        result["txid"].as_str().unwrap_or_default().to_string(),
+        result["origfee"].as_i64().unwrap_or(0) as i32,
+        result["fee"].as_i64().unwrap_or(0) as i32,
+    ))
+}
+
+/// parse_outpoint parses TXID:VOUT
+fn parse_outpoint(s: &str) -> Result<(String, i32)> {
+    match s.split_once(':') {
+        Some((txid, vout)) => Ok((txid.to_string(), vout.parse()?)),
+        None => Err(format_err!("expected TXID:VOUT, got {}", s)),
+    }
+}
+
+/// parse_payment parses ADDRESS:AMOUNT
+fn parse_payment(s: &str) -> Result<(String, i32)> {
+    match s.rsplit_once(':') {
+        Some((address, amount)) => Ok((address.to_string(), amount.parse()?)),
+        None => Err(format_err!("expected ADDRESS:AMOUNT, got {}", s)),
+    }
+}
+
+/// RecipientList is a JSON object mapping addresses to amounts, kept in the
+/// order the entries are written since the outputs follow it
+struct RecipientList(Vec<(String, Value)>);
+
+impl<'de> Deserialize<'de> for RecipientList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> Visitor<'de> for EntriesVisitor {
+            type Value = RecipientList;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an object mapping addresses to amounts")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<RecipientList, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry::<String, Value>()? {
+                    entries.push(entry);
+                }
+                Ok(RecipientList(entries))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+/// parse_recipients reads the payments of sendmany in the order given, either a
+/// JSON object mapping addresses to amounts, a JSON array of {"address", "amount"} objects, or CSV
+/// lines of address,amount with an optional header
+fn parse_recipients(data: &str) -> Result<Vec<(String, i32)>> {
+    let data = data.trim();
+    let mut outputs = Vec::new();
+    if data.starts_with('{') || data.starts_with('[') {
+        let entries: Vec<(String, Value)> = if data.starts_with('{') {
+            serde_json::from_str::<RecipientList>(data)?.0
+        } else {
+            let list: Vec<Value> = serde_json::from_str(data)?;
+            list.into_iter()
+                .map(|mut entry| {
+                    let address = entry["address"].as_str().unwrap_or_default().to_string();
+                    (address, entry["amount"].take())
+                })
+                .collect()
+        };
+        for (address, amount) in entries {
+            match amount.as_i64() {
+                Some(amount) if amount > 0 && amount <= i32::MAX as i64 => outputs.push((address, amount as i32)),
+                _ => return Err(format_err!("invalid amount {} for {}", amount, address)),
+            }
+        }
+    } else {
+        let mut first = true;
+        for (n, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let header = first;
+            first = false;
+            let (address, amount) = match line.split_once(',') {
+                Some((address, amount)) => (address.trim(), amount.trim()),
+                None => return Err(format_err!("line {}: expected ADDRESS,AMOUNT, got {}", n + 1, line)),
+            };
+            match amount.parse::<i32>() {
+                Ok(amount) if amount > 0 => outputs.push((address.to_string(), amount)),
+                _ if header => continue,
+                _ => return Err(format_err!("line {}: invalid amount {} for {}", n + 1, amount, address)),
+            }
+        }
+    }
+
+    if outputs.is_empty() {
+        return Err(format_err!("no recipient to pay"));
+    }
+    let mut seen = HashSet::new();
+    for (address, _) in &outputs {
+        if Address::decode(address).is_err() {
+            return Err(format_err!("invalid address {}", address));
+        }
+        if !seen.insert(address.clone()) {
+            return Err(format_err!("{} is paid twice", address));
+        }
+    }
+    if outputs.iter().map(|(_, amount)| *amount as i64).sum::<i64>() > i32::MAX as i64 {
+        return Err(format_err!("the total amount is too large"));
+    }
+    Ok(outputs)
+}
+
+/// cmd_sign_raw_transaction signs the inputs the local wallets own. The outputs
+/// being spent come from the --prevout options, else from the running node or
+/// the local chain. Returns the new hex and whether every input is signed
+fn cmd_sign_raw_transaction(hex: &str, prevouts: &[String], config: &Config) -> Result<(String, bool)> {
+    let mut tx = Transaction::from_hex(hex)?;
+    let mut spent = HashMap::new();
+    for p in prevouts {
+        let (outpoint, address) = match p.rsplit_once(':') {
+            Some(parts) => parts,
+            None => return Err(format_err!("expected TXID:VOUT:ADDRESS, got {}", p)),
+        };
+        spent.insert(parse_outpoint(outpoint)?, TXOutput::new(0, address.to_string())?);
+    }
+
+    let missing: Vec<(String, i32)> = tx
+        .vin
+        .iter()
+        .map(|vin| (vin.txid.clone(), vin.vout))
+        .filter(|outpoint| !spent.contains_key(outpoint))
+        .collect();
+    spent.extend(fetch_prevouts(&missing, config));
+
+    let wallets = Wallets::new()?;
+    let complete = tx.sign_with_wallets(&wallets, &spent)?;
+    Ok((tx.to_hex()?, complete))
+}
+
+/// fetch_prevouts looks the outputs up on the running node, else in the local
+/// chain. Outputs that cannot be found are left out
+fn fetch_prevouts(outpoints: &[(String, i32)], config: &Config) -> HashMap<(String, i32), TXOutput> {
+    let mut prevouts = HashMap::new();
+    if outpoints.is_empty() {
+        return prevouts;
+    }
+    let node_running = rpc::is_node_running(&config.rpc_address);
+    let bc = if node_running { None } else { Blockchain::new().ok() };
+    for (txid, vout) in outpoints {
+        let prev_tx = if node_running {
+            match rpc::call(config, "getrawtransaction", vec![Value::from(txid.as_str())]) {
+                Ok(hex) => Transaction::from_hex(hex.as_str().unwrap_or_default()).ok(),
+                Err(_) => None,
+            }
+        } else {
+            bc.as_ref().and_then(|bc| bc.find_transacton(txid).ok())
+        };
+        if let Some(out) = prev_tx.and_then(|t| t.vout.get(*vout as usize).cloned()) {
+            prevouts.insert((txid.clone(), *vout), out);
+        }
+    }
+    prevouts
+}
+
+/// sync_ledger brings the wallet transaction index up to date
+fn sync_ledger(config: &Config) -> Result<Ledger> {
+    let owned = Wallets::new()?.get_owned_addresses();
+    let chain = ledger::open_chain(config)?;
+    Ledger::sync(chain.as_ref(), &owned)
+}
+
+fn wallet_tx_to_json(wtx: &WalletTx, confirmations: i32) -> Value {
+    json!({
+        "txid": wtx.txid,
+        "category": wtx.category(),
+        "amount": wtx.amount(),
+        "fee": wtx.fee,
+        "confirmations": confirmations,
+        "blockhash": wtx.block_hash,
+        "blockheight": wtx.height,
+        "time": wtx.time,
+        "counterparties": wtx.counterparties,
+    })
+}
+
+/// read_psbt accepts either a file holding a PSBT or the base64 string itself
+fn read_psbt(arg: &str) -> Result<Psbt> {
+    if Path::new(arg).is_file() {
+        return Psbt::from_base64(&std::fs::read_to_string(arg)?);
+    }
+    Psbt::from_base64(arg)
+}
+
+/// write_psbt saves psbt to out when given, else prints it
+fn write_psbt(psbt: &Psbt, out: Option<&String>) -> Result<()> {
+    let data = psbt.to_base64()?;
+    match out {
+        Some(path) => {
+            std::fs::write(path, data + "\n")?;
+            println!("PSBT written to {}", path);
+        }
+        None => println!("{}", data),
+    }
+    Ok(())
+}
+
+fn psbt_to_json(psbt: &Psbt) -> Value {
+    let inputs: Vec<Value> = psbt
+        .inputs
+        .iter()
+        .map(|input| {
+            let prevout = input.prevout.as_ref().map(|out| {
+                json!({
+                    "value": out.value,
+                    "address": address_from_pub_key_hash(&out.pub_key_hash),
+                })
+            });
+            let partial_sigs: serde_json::Map<String, Value> = input
+                .partial_sigs
+                .iter()
+                .map(|(pub_key, sig)| (hex::encode(pub_key), json!(hex::encode(sig))))
+                .collect();
+            json!({
+                "prevout": prevout,
+                "partial_signatures": partial_sigs,
+                "finalized": !input.final_signature.is_empty(),
+            })
+        })
+        .collect();
+    let fee = psbt
+        .inputs
+        .iter()
+        .map(|i| i.prevout.as_ref().map(|out| out.value))
+        .sum::<Option<i32>>()
+        .map(|total_in| total_in - psbt.tx.vout.iter().map(|out| out.value).sum::<i32>());
+    json!({
+        "tx": rpc::tx_to_json(&psbt.tx),
+        "inputs": inputs,
+        "fee": fee,
+    })
+}
+
+/// cmd_create_wallet adds a wallet address, through the running node when there
+/// is one since it holds the keys of an unlocked encrypted wallet
+fn cmd_create_wallet(config: &Config) -> Result<String> {
+    if rpc::is_node_running(&config.rpc_address) {
+        let address = rpc::call(config, "getnewaddress", vec![])?;
+        return Ok(address.as_str().unwrap_or_default().to_string());
+    }
     let mut ws = Wallets::new()?;
-    let address = ws.create_wallet();
+    let address = ws.create_wallet()?;
     ws.save_all()?;
     Ok(address)
 }
 
+fn cmd_create_hd_wallet() -> Result<(String, String)> {
+    let mut ws = Wallets::new()?;
+    let mnemonic = ws.new_hd_seed()?;
+    let address = ws.create_wallet()?;
+    ws.save_all()?;
+    Ok((mnemonic, address))
+}
+
+/// cmd_restore_wallet restores the HD seed and the addresses the chain shows were
+/// used, read through the running node if there is one
+fn cmd_restore_wallet(mnemonic: &str, gap_limit: u32, config: &Config) -> Result<Vec<String>> {
+    let chain = ledger::open_chain(config)
+        .map_err(|e| format_err!("could not read the chain to find the used addresses: {}", e))?;
+    let mut used = HashSet::new();
+    let mut txs = chain.mempool()?;
+    let mut hash = chain.best_block_hash()?;
+    while !hash.is_empty() {
+        let block = chain.get_block(&hash)?;
+        hash = block.get_prev_hash();
+        txs.extend(block.get_transaction().iter().cloned());
+    }
+    for tx in &txs {
+        for out in &tx.vout {
+            used.insert(address_from_pub_key_hash(&out.pub_key_hash));
+        }
+    }
+
+    let mut ws = Wallets::new()?;
+    let addresses = ws.restore_hd_seed(mnemonic, |address| used.contains(address), gap_limit)?;
+    ws.save_all()?;
+    Ok(addresses)
+}
+
 fn cmd_reindex() -> Result<i32> {
     let bc = Blockchain::new()?;
     let utxo_set = UTXOSet { blockchain: bc };
@@ -196,20 +1064,44 @@ fn cmd_create_blockchain(address: &str) -> Result<()> {
 fn cmd_get_balance(address: &str) -> Result<i32> {
     let pub_key_hash = Address::decode(address).unwrap().body;
     let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
-    let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
+    let utxo_set = UTXOSet::new(bc)?;
+    let utxos = utxo_set.find_utxo(&pub_key_hash)?;
 
     let mut balance = 0;
-    for out in utxos.outputs {
+    for out in utxos {
         balance += out.value;
     }
     Ok(balance)
 }
 
-fn cmd_print_chain() -> Result<()> {
-    let bc = Blockchain::new()?;
-    for b in bc.iter() {
-        println!("{:#?}", b);
+fn cmd_bench_pow(threads: usize, seconds: u64) -> Result<()> {
+    println!("benchmark proof of work on {} threads for {}s", threads, seconds);
+    let duration = Duration::from_secs(seconds);
+    let hashes = AtomicU64::new(0);
+    let start = Instant::now();
+    let mut blocks = 0;
+    while start.elapsed() < duration {
+        let mut block = Block::new_template(Vec::new(), blocks.to_string(), blocks)?;
+        if block.mine(threads, || start.elapsed() >= duration, &hashes)? {
+            blocks += 1;
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let hashes = hashes.load(Ordering::Relaxed);
+    println!("{} hashes, {} blocks in {:.2}s", hashes, blocks, elapsed);
+    println!("hashrate: {:.0} H/s", hashes as f64 / elapsed);
+    Ok(())
+}
+
+/// cmd_print_chain prints the blocks from the tip down, read through the
+/// running node when there is one
+fn cmd_print_chain(config: &Config) -> Result<()> {
+    let chain = ledger::open_chain(config)?;
+    let mut hash = chain.best_block_hash()?;
+    while !hash.is_empty() {
+        let block = chain.get_block(&hash)?;
+        println!("{:#?}", block);
+        hash = block.get_prev_hash();
     }
     Ok(())
 }
@@ -221,5 +1113,47 @@ fn cmd_list_address() -> Result<()> {
     for ad in addresses {
         println!("{}", ad);
     }
+    for ad in ws.get_watch_only_addresses() {
+        println!("{} (watch-only)", ad);
+    }
+    Ok(())
+}
+
+/// cmd_dump_wallet writes the wallet dump to a file only the owner can read
+fn cmd_dump_wallet(path: &str) -> Result<()> {
+    let dump = Wallets::new()?.dump()?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(dump.as_bytes())?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_recipients() {
+        let a1 = address_from_pub_key_hash(&[1; 20]);
+        let a2 = address_from_pub_key_hash(&[2; 20]);
+        let expected = vec![(a1.clone(), 5), (a2.clone(), 7)];
+
+        let csv = format!("address,amount\n{},5\n\n# second\n{}, 7\n", a1, a2);
+        assert_eq!(parse_recipients(&csv).unwrap(), expected);
+        let list = format!(r#"[{{"address": "{}", "amount": 5}}, {{"address": "{}", "amount": 7}}]"#, a1, a2);
+        assert_eq!(parse_recipients(&list).unwrap(), expected);
+        let object = format!(r#"{{"{}": 7, "{}": 5}}"#, a2, a1);
+        assert_eq!(parse_recipients(&object).unwrap(), vec![(a2.clone(), 7), (a1.clone(), 5)]);
+        assert!(parse_recipients(&format!(r#"{{"{}": 5, "{}": 6}}"#, a1, a1)).is_err());
+
+        assert!(parse_recipients(&format!("{},5\n{},0", a1, a2)).is_err());
+        assert!(parse_recipients(&format!("{},5\n{},6", a1, a1)).is_err());
+        assert!(parse_recipients("nowhere,5").is_err());
+        assert!(parse_recipients("").is_err());
+    }
+}
@@ -0,0 +1,213 @@
+//! coin selection strategies for wallet sends
+
+use super::*;
+use crate::transaction::TXOutput;
+use crate::wallet::address_from_pub_key_hash;
+use failure::format_err;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// DEFAULT_STRATEGY is the coin selection used when none is asked for
+pub const DEFAULT_STRATEGY: &str = "bnb";
+/// BNB_MAX_TRIES bounds the branch and bound search before it gives up
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// Coin is a spendable wallet output
+#[derive(Debug, Clone)]
+pub struct Coin {
+    pub txid: String,
+    pub vout: i32,
+    pub output: TXOutput,
+}
+
+impl Coin {
+    pub fn value(&self) -> i32 {
+        self.output.value
+    }
+
+    pub fn address(&self) -> String {
+        address_from_pub_key_hash(&self.output.pub_key_hash)
+    }
+}
+
+/// CoinSelector picks the coins funding a payment
+pub trait CoinSelector {
+    /// Select returns coins worth at least target, None when all the coins are not enough
+    fn select(&self, coins: &[Coin], target: i32) -> Option<Vec<Coin>>;
+}
+
+/// LargestFirst spends the biggest coins first, using as few inputs as possible
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, coins: &[Coin], target: i32) -> Option<Vec<Coin>> {
+        let mut sorted = coins.to_vec();
+        sorted.sort_by_key(|c| Reverse(c.value()));
+        let mut selected = Vec::new();
+        let mut total: i64 = 0;
+        for coin in sorted {
+            if total >= target as i64 {
+                break;
+            }
+            total += coin.value() as i64;
+            selected.push(coin);
+        }
+        if total >= target as i64 {
+            Some(selected)
+        } else {
+            None
+        }
+    }
+}
+
+/// BranchAndBound searches for coins adding up to exactly the target so that the
+/// transaction needs no change output, falling back to largest first
+pub struct BranchAndBound;
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, coins: &[Coin], target: i32) -> Option<Vec<Coin>> {
+        let mut sorted = coins.to_vec();
+        sorted.sort_by_key(|c| Reverse(c.value()));
+        let values: Vec<i64> = sorted.iter().map(|c| c.value() as i64).collect();
+        // remaining[i] is the value of the coins from i on, to prune branches that cannot reach the target
+        let mut remaining = vec![0; values.len() + 1];
+        for i in (0..values.len()).rev() {
+            remaining[i] = remaining[i + 1] + values[i];
+        }
+
+        let mut search = BnbSearch {
+            values: &values,
+            remaining: &remaining,
+            target: target as i64,
+            selection: Vec::new(),
+            tries: 0,
+        };
+        if search.run(0, 0) {
+            return Some(search.selection.iter().map(|i| sorted[*i].clone()).collect());
+        }
+        LargestFirst.select(coins, target)
+    }
+}
+
+struct BnbSearch<'a> {
+    values: &'a [i64],
+    remaining: &'a [i64],
+    target: i64,
+    selection: Vec<usize>,
+    tries: usize,
+}
+
+impl BnbSearch<'_> {
+    /// run explores including then excluding the coin at index, returns true once
+    /// the selection matches the target
+    fn run(&mut self, index: usize, sum: i64) -> bool {
+        if sum == self.target {
+            return true;
+        }
+        self.tries += 1;
+        if index == self.values.len()
+            || sum > self.target
+            || sum + self.remaining[index] < self.target
+            || self.tries > BNB_MAX_TRIES
+        {
+            return false;
+        }
+
+        self.selection.push(index);
+        if self.run(index + 1, sum + self.values[index]) {
+            return true;
+        }
+        self.selection.pop();
+
+        // leaving out a coin also leaves out the next ones of the same value,
+        // those branches were just explored
+        let mut next = index + 1;
+        while next < self.values.len() && self.values[next] == self.values[index] {
+            next += 1;
+        }
+        self.run(next, sum)
+    }
+}
+
+/// Privacy spends every coin of an address at once so that no address is left
+/// half spent and as few addresses as possible are linked together. It picks the
+/// smallest address covering the target, else combines the richest addresses
+pub struct Privacy;
+
+impl CoinSelector for Privacy {
+    fn select(&self, coins: &[Coin], target: i32) -> Option<Vec<Coin>> {
+        let mut groups: HashMap<String, Vec<Coin>> = HashMap::new();
+        for coin in coins {
+            groups.entry(coin.address()).or_default().push(coin.clone());
+        }
+        let mut groups: Vec<(i64, Vec<Coin>)> = groups
+            .into_values()
+            .map(|g| (g.iter().map(|c| c.value() as i64).sum(), g))
+            .collect();
+        groups.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1[0].address().cmp(&b.1[0].address())));
+
+        if let Some((_, group)) = groups.iter().find(|(total, _)| *total >= target as i64) {
+            return Some(group.clone());
+        }
+        let mut selected = Vec::new();
+        let mut total = 0;
+        for (value, group) in groups.into_iter().rev() {
+            total += value;
+            selected.extend(group);
+            if total >= target as i64 {
+                return Some(selected);
+            }
+        }
+        None
+    }
+}
+
+/// selector returns the coin selection strategy called name
+pub fn selector(name: &str) -> Result<Box<dyn CoinSelector>> {
+    match name {
+        "largest" => Ok(Box::new(LargestFirst)),
+        "bnb" => Ok(Box::new(BranchAndBound)),
+        "privacy" => Ok(Box::new(Privacy)),
+        _ => Err(format_err!("unknown coin selection {}, expected largest, bnb or privacy", name)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn coin(n: i32, value: i32, owner: u8) -> Coin {
+        Coin {
+            txid: format!("tx{}", n),
+            vout: 0,
+            output: TXOutput {
+                value,
+                pub_key_hash: vec![owner; 20],
+            },
+        }
+    }
+
+    fn values(coins: Option<Vec<Coin>>) -> Vec<i32> {
+        let mut values: Vec<i32> = coins.unwrap().iter().map(|c| c.value()).collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn test_select() {
+        let coins = vec![coin(0, 5, 1), coin(1, 7, 1), coin(2, 3, 2), coin(3, 10, 3)];
+        assert_eq!(values(LargestFirst.select(&coins, 12)), vec![7, 10]);
+        assert!(LargestFirst.select(&coins, 26).is_none());
+
+        assert_eq!(values(BranchAndBound.select(&coins, 8)), vec![3, 5]);
+        assert_eq!(values(BranchAndBound.select(&coins, 25)), vec![3, 5, 7, 10]);
+        // no exact match, largest first
+        assert_eq!(values(BranchAndBound.select(&coins, 21)), vec![5, 7, 10]);
+
+        // the address holding 5 and 7 is the smallest covering 11
+        assert_eq!(values(Privacy.select(&coins, 11)), vec![5, 7]);
+        assert_eq!(values(Privacy.select(&coins, 4)), vec![10]);
+        assert_eq!(values(Privacy.select(&coins, 14)), vec![5, 7, 10]);
+        assert!(Privacy.select(&coins, 26).is_none());
+    }
+}
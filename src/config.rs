@@ -0,0 +1,214 @@
+//! node configuration
+
+use super::*;
+use crate::block::available_threads;
+use crate::mempool::{DEFAULT_MAX_MEMPOOL_SIZE, DEFAULT_MIN_RELAY_FEE};
+use crate::transaction::{COINBASE_MATURITY, DEVNET_COINBASE_MATURITY};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use log::info;
+
+pub const DEFAULT_CONFIG_FILE: &str = "data/config.json";
+/// DEFAULT_MEMPOOL_EXPIRY is two weeks, in hours
+const DEFAULT_MEMPOOL_EXPIRY: u64 = 336;
+
+/// Config holds the node settings read from the config file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// seed_nodes are contacted on startup to join the network
+    pub seed_nodes: Vec<String>,
+    /// connect restricts the node to only these peers when not empty
+    pub connect: Vec<String>,
+    /// addnode adds peers on top of the seed nodes
+    pub addnode: Vec<String>,
+    /// bind lists the local interfaces to listen on, IPv4 or IPv6, with or without port,
+    /// localhost only when empty, "0.0.0.0" or "::" listen on every interface
+    pub bind: Vec<String>,
+    /// external_address is the address advertised to other nodes
+    pub external_address: Option<String>,
+    /// network_threads is the number of workers handling peer connections
+    pub network_threads: usize,
+    /// mine_empty_blocks keeps the miner busy when the mempool is empty
+    pub mine_empty_blocks: bool,
+    /// mining_threads is the number of threads searching for the proof of work
+    pub mining_threads: usize,
+    /// coinbase_maturity is how many blocks a coinbase output waits before it can be spent
+    pub coinbase_maturity: i32,
+    /// devnet runs a test network whose coinbase outputs mature after one block
+    pub devnet: bool,
+    /// persist_mempool saves the mempool on shutdown and reloads it on startup
+    pub persist_mempool: bool,
+    /// mempool_expiry is how many hours a transaction may wait in the mempool
+    pub mempool_expiry: u64,
+    /// max_mempool_size bounds the mempool, in bytes, the lowest paying transactions are evicted past it
+    pub max_mempool_size: usize,
+    /// min_relay_fee is the fee per 1000 bytes a transaction pays to enter the
+    /// mempool, wallet sends pay it too
+    pub min_relay_fee: i32,
+    /// rpc_enabled turns the local JSON-RPC endpoint on
+    pub rpc_enabled: bool,
+    /// rpc_address is where the JSON-RPC endpoint listens and where the CLI looks for it
+    pub rpc_address: String,
+    /// rpc_user and rpc_password are accepted by the JSON-RPC endpoint next to the cookie
+    pub rpc_user: Option<String>,
+    pub rpc_password: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            seed_nodes: vec![String::from("localhost:3000")],
+            connect: Vec::new(),
+            addnode: Vec::new(),
+            bind: Vec::new(),
+            external_address: None,
+            network_threads: 4,
+            mine_empty_blocks: false,
+            mining_threads: available_threads(),
+            coinbase_maturity: COINBASE_MATURITY,
+            devnet: false,
+            persist_mempool: true,
+            mempool_expiry: DEFAULT_MEMPOOL_EXPIRY,
+            max_mempool_size: DEFAULT_MAX_MEMPOOL_SIZE,
+            min_relay_fee: DEFAULT_MIN_RELAY_FEE,
+            rpc_enabled: true,
+            rpc_address: String::from("127.0.0.1:8332"),
+            rpc_user: None,
+            rpc_password: None,
+        }
+    }
+}
+
+impl Config {
+    /// Load reads the config file, falling back to the defaults when it does not exist
+    pub fn load(path: &str) -> Result<Config> {
+        if !Path::new(path).exists() {
+            return Ok(Config::default());
+        }
+        info!("load config from: {}", path);
+        let data = std::fs::read_to_string(path)?;
+        let mut config: Config = serde_json::from_str(&data)?;
+        if config.devnet {
+            config.use_devnet();
+        }
+        Ok(config)
+    }
+
+    /// UseDevnet switches to the test network rules, where a coinbase output
+    /// can be spent in the next block
+    pub fn use_devnet(&mut self) {
+        self.devnet = true;
+        self.coinbase_maturity = DEVNET_COINBASE_MATURITY;
+    }
+
+    /// BootstrapNodes returns the peers the node talks to on startup
+    pub fn bootstrap_nodes(&self) -> Vec<String> {
+        if !self.connect.is_empty() {
+            return self.connect.clone();
+        }
+        let mut nodes = self.seed_nodes.clone();
+        for node in &self.addnode {
+            if !nodes.contains(node) {
+                nodes.push(node.clone());
+            }
+        }
+        nodes
+    }
+
+    /// ListenAddresses returns the socket addresses to listen on for port
+    pub fn listen_addresses(&self, port: &str) -> Vec<String> {
+        if self.bind.is_empty() {
+            return vec![with_port("localhost", port)];
+        }
+        self.bind.iter().map(|b| with_port(b, port)).collect()
+    }
+
+    /// RpcBind returns the address the JSON-RPC endpoint listens on, if enabled
+    pub fn rpc_bind(&self) -> Option<String> {
+        if self.rpc_enabled {
+            Some(self.rpc_address.clone())
+        } else {
+            None
+        }
+    }
+
+    /// RpcCredentials returns the configured user and password, if both are set
+    pub fn rpc_credentials(&self) -> Option<(String, String)> {
+        match (&self.rpc_user, &self.rpc_password) {
+            (Some(user), Some(password)) => Some((user.clone(), password.clone())),
+            _ => None,
+        }
+    }
+
+    /// AdvertisedAddress returns the address other nodes should use to reach us
+    pub fn advertised_address(&self, port: &str) -> String {
+        match &self.external_address {
+            Some(addr) => with_port(addr, port),
+            None => with_port("localhost", port),
+        }
+    }
+}
+
+/// with_port appends port to host unless it already carries one,
+/// bracketing IPv6 literals
+fn with_port(host: &str, port: &str) -> String {
+    if host.parse::<SocketAddr>().is_ok() {
+        return host.to_string();
+    }
+    let bare = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = bare.parse::<IpAddr>() {
+        return match ip {
+            IpAddr::V4(ip) => format!("{}:{}", ip, port),
+            IpAddr::V6(ip) => format!("[{}]:{}", ip, port),
+        };
+    }
+    if host.contains(':') {
+        return host.to_string();
+    }
+    format!("{}:{}", host, port)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bootstrap_nodes() {
+        let mut config: Config = serde_json::from_str(r#"{"addnode": ["localhost:3001"]}"#).unwrap();
+        assert_eq!(
+            config.bootstrap_nodes(),
+            vec!["localhost:3000".to_string(), "localhost:3001".to_string()]
+        );
+
+        config.connect = vec![String::from("10.0.0.2:3000")];
+        assert_eq!(config.bootstrap_nodes(), vec!["10.0.0.2:3000".to_string()]);
+    }
+
+    #[test]
+    fn test_devnet() {
+        let mut config = Config::default();
+        assert_eq!(config.coinbase_maturity, COINBASE_MATURITY);
+        config.use_devnet();
+        assert_eq!(config.coinbase_maturity, DEVNET_COINBASE_MATURITY);
+    }
+
+    #[test]
+    fn test_listen_addresses() {
+        let mut config = Config::default();
+        assert_eq!(config.listen_addresses("3000"), vec!["localhost:3000".to_string()]);
+        assert_eq!(config.advertised_address("3000"), "localhost:3000");
+
+        config.bind = vec![String::from("0.0.0.0")];
+        assert_eq!(config.listen_addresses("3000"), vec!["0.0.0.0:3000".to_string()]);
+
+        config.bind = vec![String::from("10.0.0.5"), String::from("::"), String::from("[::1]:4000")];
+        config.external_address = Some(String::from("2001:db8::7"));
+        assert_eq!(
+            config.listen_addresses("3000"),
+            vec!["10.0.0.5:3000".to_string(), "[::]:3000".to_string(), "[::1]:4000".to_string()]
+        );
+        assert_eq!(config.advertised_address("3000"), "[2001:db8::7]:3000");
+    }
+}
@@ -0,0 +1,112 @@
+//! block download scheduling
+//!
+//! Blocks announced by peers are requested one at a time, in the order they
+//! were announced, which is parents first so that each block arrives after
+//! the one it extends. A request left unanswered for too long is sent again to
+//! another peer which announced the block, the stalling peer is not asked for
+//! that block again.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+pub struct BlockDownload {
+    queue: VecDeque<String>,
+    /// sources are the peers which announced each queued or requested block
+    sources: HashMap<String, Vec<String>>,
+    /// in_flight is the block requested, the peer asked and when
+    in_flight: Option<(String, String, Instant)>,
+    timeout: Duration,
+}
+
+impl BlockDownload {
+    pub fn new(timeout: Duration) -> BlockDownload {
+        BlockDownload {
+            queue: VecDeque::new(),
+            sources: HashMap::new(),
+            in_flight: None,
+            timeout,
+        }
+    }
+
+    /// Announce records that peer has the blocks hashes, queuing the new ones
+    pub fn announce(&mut self, peer: &str, hashes: &[String]) {
+        for hash in hashes {
+            let sources = self.sources.entry(hash.clone()).or_default();
+            if sources.is_empty() {
+                self.queue.push_back(hash.clone());
+            }
+            if !sources.iter().any(|p| p == peer) {
+                sources.push(peer.to_string());
+            }
+        }
+    }
+
+    /// Received forgets a block which arrived, requested or not
+    pub fn received(&mut self, hash: &str) {
+        if self.sources.remove(hash).is_some() {
+            self.queue.retain(|h| h != hash);
+        }
+        if matches!(&self.in_flight, Some((h, _, _)) if h == hash) {
+            self.in_flight = None;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// NextRequest returns the block to request now and the peer to ask, if no
+    /// request is waiting for an answer. A request older than the timeout is
+    /// given up and the block asked to its next source, dropped when none is left
+    pub fn next_request(&mut self, now: Instant) -> Option<(String, String)> {
+        if let Some((hash, peer, at)) = self.in_flight.take() {
+            if now.duration_since(at) < self.timeout {
+                self.in_flight = Some((hash, peer, at));
+                return None;
+            }
+            if let Some(sources) = self.sources.get_mut(&hash) {
+                sources.retain(|p| *p != peer);
+                if sources.is_empty() {
+                    self.sources.remove(&hash);
+                } else {
+                    self.queue.push_front(hash);
+                }
+            }
+        }
+
+        while let Some(hash) = self.queue.pop_front() {
+            if let Some(peer) = self.sources.get(&hash).and_then(|s| s.first()).cloned() {
+                self.in_flight = Some((hash.clone(), peer.clone(), now));
+                return Some((hash, peer));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_block_download() {
+        let hashes = |ids: &[&str]| ids.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+        let request = |hash: &str, peer: &str| Some((hash.to_string(), peer.to_string()));
+        let mut download = BlockDownload::new(Duration::from_secs(10));
+        download.announce("peer1", &hashes(&["b1", "b2"]));
+        download.announce("peer2", &hashes(&["b2"]));
+
+        let now = Instant::now();
+        assert_eq!(download.next_request(now), request("b1", "peer1"));
+        assert_eq!(download.next_request(now), None);
+        download.received("b1");
+        assert_eq!(download.next_request(now), request("b2", "peer1"));
+
+        // peer1 stalls, peer2 is asked
+        let later = now + Duration::from_secs(10);
+        assert_eq!(download.next_request(later), request("b2", "peer2"));
+        // no one else has it
+        assert_eq!(download.next_request(later + Duration::from_secs(10)), None);
+        assert!(download.is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+//! hierarchical deterministic key derivation for ed25519 (SLIP-0010)
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha512;
+
+/// HARDENED is added to an index to derive a hardened child, ed25519 only has those
+const HARDENED: u32 = 0x8000_0000;
+/// WALLET_PATH is the account path the receiving addresses are derived under, m/44'/0'/0'/0'
+pub const WALLET_PATH: [u32; 4] = [44, 0, 0, 0];
+
+/// ExtendedKey is a private key together with the chain code needed to derive its children
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtendedKey {
+    pub key: [u8; 32],
+    pub chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    /// NewMaster derives the root key from a wallet seed
+    pub fn new_master(seed: &[u8]) -> ExtendedKey {
+        ExtendedKey::from_hmac(b"ed25519 seed", seed)
+    }
+
+    /// DeriveChild derives the hardened child index
+    pub fn derive_child(&self, index: u32) -> ExtendedKey {
+        let mut data = vec![0];
+        data.extend_from_slice(&self.key);
+        data.extend_from_slice(&(index | HARDENED).to_be_bytes());
+        ExtendedKey::from_hmac(&self.chain_code, &data)
+    }
+
+    /// DerivePath derives the key at path, every step hardened
+    pub fn derive_path(&self, path: &[u32]) -> ExtendedKey {
+        path.iter().fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+
+    fn from_hmac(key: &[u8], data: &[u8]) -> ExtendedKey {
+        let mut hmac = Hmac::new(Sha512::new(), key);
+        hmac.input(data);
+        let result = hmac.result();
+        let (il, ir) = result.code().split_at(32);
+        let mut extended = ExtendedKey {
+            key: [0; 32],
+            chain_code: [0; 32],
+        };
+        extended.key.copy_from_slice(il);
+        extended.chain_code.copy_from_slice(ir);
+        extended
+    }
+}
+
+/// derive_wallet_key returns the key of the receiving address number index
+pub fn derive_wallet_key(seed: &[u8], index: u32) -> [u8; 32] {
+    let mut path = WALLET_PATH.to_vec();
+    path.push(index);
+    ExtendedKey::new_master(seed).derive_path(&path).key
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_slip10_vector() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedKey::new_master(&seed);
+        assert_eq!(
+            hex::encode(master.chain_code),
+            "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb"
+        );
+        assert_eq!(
+            hex::encode(master.key),
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7"
+        );
+
+        let child = master.derive_path(&[0]);
+        assert_eq!(
+            hex::encode(child.chain_code),
+            "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69"
+        );
+        assert_eq!(
+            hex::encode(child.key),
+            "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3"
+        );
+        assert_ne!(derive_wallet_key(&seed, 0), derive_wallet_key(&seed, 1));
+    }
+}
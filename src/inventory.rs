@@ -0,0 +1,106 @@
+//! per peer inventory tracking for transaction relay
+//!
+//! Each peer remembers the transactions it announced to us or we announced to
+//! it, so that nothing is sent back to a peer which already has it. New
+//! transactions are queued per peer and announced in batches at random
+//! intervals, which hides the node a transaction came from and saves messages.
+
+use rand::Rng;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// MAX_KNOWN_INVENTORY bounds the ids remembered per peer, the oldest are forgotten first
+const MAX_KNOWN_INVENTORY: usize = 50_000;
+/// INV_BROADCAST_MAX is the most ids announced to a peer at once
+const INV_BROADCAST_MAX: usize = 1000;
+/// INV_BROADCAST_INTERVAL is the average delay between two announcements to a peer
+const INV_BROADCAST_INTERVAL: Duration = Duration::from_secs(2);
+
+/// PeerInventory is what a peer knows and what is waiting to be announced to it
+pub struct PeerInventory {
+    known: HashSet<String>,
+    /// order keeps the known ids oldest first
+    order: VecDeque<String>,
+    queue: Vec<String>,
+    next_send: Instant,
+}
+
+impl PeerInventory {
+    pub fn new() -> PeerInventory {
+        PeerInventory {
+            known: HashSet::new(),
+            order: VecDeque::new(),
+            queue: Vec::new(),
+            next_send: Instant::now() + poisson_delay(INV_BROADCAST_INTERVAL),
+        }
+    }
+
+    pub fn knows(&self, id: &str) -> bool {
+        self.known.contains(id)
+    }
+
+    /// AddKnown records that the peer has id
+    pub fn add_known(&mut self, id: &str) {
+        if !self.known.insert(id.to_string()) {
+            return;
+        }
+        self.order.push_back(id.to_string());
+        if self.order.len() > MAX_KNOWN_INVENTORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.known.remove(&oldest);
+            }
+        }
+    }
+
+    /// Push queues id for the next announcement unless the peer already has it
+    pub fn push(&mut self, id: &str) {
+        if !self.knows(id) {
+            self.add_known(id);
+            self.queue.push(id.to_string());
+        }
+    }
+
+    /// TakeDue returns the ids to announce now, at most INV_BROADCAST_MAX, once
+    /// the random delay since the last announcement has passed
+    pub fn take_due(&mut self, now: Instant) -> Vec<String> {
+        if now < self.next_send {
+            return Vec::new();
+        }
+        self.next_send = now + poisson_delay(INV_BROADCAST_INTERVAL);
+        let count = self.queue.len().min(INV_BROADCAST_MAX);
+        self.queue.drain(..count).collect()
+    }
+}
+
+/// poisson_delay draws the delay until the next event of a Poisson process
+/// averaging one event per mean
+fn poisson_delay(mean: Duration) -> Duration {
+    let u: f64 = rand::thread_rng().gen_range(f64::EPSILON..1.0);
+    mean.mul_f64(-u.ln())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_peer_inventory() {
+        let mut inv = PeerInventory::new();
+        inv.add_known("a");
+        inv.push("a");
+        inv.push("b");
+        inv.push("b");
+        assert!(inv.knows("b"));
+
+        let now = Instant::now();
+        inv.next_send = now + Duration::from_secs(1);
+        assert!(inv.take_due(now).is_empty());
+        assert_eq!(inv.take_due(now + Duration::from_secs(1)), vec!["b"]);
+
+        for i in 0..MAX_KNOWN_INVENTORY {
+            inv.add_known(&i.to_string());
+        }
+        assert!(!inv.knows("a"));
+        assert!(inv.knows("0"));
+    }
+}
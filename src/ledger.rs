@@ -0,0 +1,438 @@
+//! wallet transaction history
+
+use super::*;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::config::Config;
+use crate::rpc;
+use crate::transaction::Transaction;
+use crate::wallet::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use log::info;
+
+/// LEDGER_TREE is the tree of the wallet database holding the indexed transactions
+const LEDGER_TREE: &str = "ledger";
+/// TIP_ENTRY records up to which block the ledger is indexed, txids never start with 0x00
+const TIP_ENTRY: &[u8] = b"\0tip";
+
+/// ChainView is how the wallet reads the chain, either from the local
+/// database or from the running node
+pub trait ChainView {
+    fn best_block_hash(&self) -> Result<String>;
+    fn get_block(&self, hash: &str) -> Result<Block>;
+    /// Mempool returns the unconfirmed transactions, empty when no node is running
+    fn mempool(&self) -> Result<Vec<Transaction>>;
+}
+
+pub struct LocalChain {
+    bc: Blockchain,
+}
+
+impl ChainView for LocalChain {
+    fn best_block_hash(&self) -> Result<String> {
+        Ok(self.bc.tip.clone())
+    }
+
+    fn get_block(&self, hash: &str) -> Result<Block> {
+        self.bc.get_block(hash)
+    }
+
+    fn mempool(&self) -> Result<Vec<Transaction>> {
+        Ok(Vec::new())
+    }
+}
+
+pub struct RpcChain {
+    config: Config,
+}
+
+impl ChainView for RpcChain {
+    fn best_block_hash(&self) -> Result<String> {
+        let hash = rpc::call(&self.config, "getbestblockhash", Vec::new())?;
+        Ok(hash.as_str().unwrap_or_default().to_string())
+    }
+
+    fn get_block(&self, hash: &str) -> Result<Block> {
+        let data = rpc::call(&self.config, "getblock", vec![Value::from(hash), Value::from(false)])?;
+        let block = bincode::deserialize(&hex::decode(data.as_str().unwrap_or_default())?)?;
+        Ok(block)
+    }
+
+    fn mempool(&self) -> Result<Vec<Transaction>> {
+        let txids = rpc::call(&self.config, "getrawmempool", Vec::new())?;
+        let mut txs = Vec::new();
+        for txid in txids.as_array().cloned().unwrap_or_default() {
+            // the transaction may have been mined since the list was taken
+            if let Ok(hex) = rpc::call(&self.config, "getrawtransaction", vec![txid]) {
+                txs.push(Transaction::from_hex(hex.as_str().unwrap_or_default())?);
+            }
+        }
+        Ok(txs)
+    }
+}
+
+/// open_chain reads the chain through the running node if there is one,
+/// else from the local database
+pub fn open_chain(config: &Config) -> Result<Box<dyn ChainView>> {
+    if rpc::is_node_running(&config.rpc_address) {
+        return Ok(Box::new(RpcChain { config: config.clone() }));
+    }
+    Ok(Box::new(LocalChain { bc: Blockchain::new()? }))
+}
+
+/// WalletOutput is an output paying one of the wallet addresses
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalletOutput {
+    pub vout: i32,
+    pub address: String,
+    pub value: i32,
+    /// spent_by is the transaction spending the output, confirmed or not
+    pub spent_by: Option<String>,
+}
+
+/// WalletTx is a transaction paying to or spending from the wallet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalletTx {
+    pub txid: String,
+    pub coinbase: bool,
+    /// block_hash, height and time are None while the transaction is in the mempool
+    pub block_hash: Option<String>,
+    pub height: Option<i32>,
+    pub time: Option<u128>,
+    /// pos is the index of the transaction in its block
+    pub pos: usize,
+    pub received: Vec<WalletOutput>,
+    /// sent is the value of the wallet outputs the transaction spends
+    pub sent: i32,
+    /// fee is only known when every input spends a wallet output
+    pub fee: Option<i32>,
+    /// counterparties are the addresses paid by a send, or paying a receive
+    pub counterparties: Vec<String>,
+}
+
+impl WalletTx {
+    /// Amount is the net change of the wallet balance
+    pub fn amount(&self) -> i32 {
+        self.received.iter().map(|out| out.value).sum::<i32>() - self.sent
+    }
+
+    pub fn category(&self) -> &'static str {
+        if self.coinbase {
+            "generate"
+        } else if self.sent > 0 {
+            "send"
+        } else {
+            "receive"
+        }
+    }
+}
+
+/// Balance splits the unspent wallet outputs by how settled they are
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Balance {
+    pub confirmed: i32,
+    /// unconfirmed are the outputs in the mempool or with fewer confirmations than asked
+    pub unconfirmed: i32,
+    /// immature are the coinbase outputs that cannot be spent yet
+    pub immature: i32,
+}
+
+/// LedgerTip is the last block indexed and the addresses the chain was scanned for
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LedgerTip {
+    hash: String,
+    height: i32,
+    addresses: Vec<String>,
+}
+
+/// Ledger indexes the transactions of the wallet addresses
+pub struct Ledger {
+    txs: HashMap<String, WalletTx>,
+    tip: Option<LedgerTip>,
+}
+
+impl Ledger {
+    /// Sync loads the stored ledger, indexes the blocks added since and the
+    /// mempool. The chain is scanned again from the start only for addresses
+    /// added since the last sync, the ledger is rebuilt when an address was
+    /// removed or the indexed tip left the chain
+    pub fn sync(chain: &dyn ChainView, owned: &HashSet<String>) -> Result<Ledger> {
+        let db = open_db()?;
+        let tree = db.open_tree(LEDGER_TREE)?;
+        let mut ledger = Ledger {
+            txs: HashMap::new(),
+            tip: None,
+        };
+        for item in tree.iter() {
+            let (k, v) = item?;
+            if k == TIP_ENTRY {
+                // a tip stored in an older format is rebuilt
+                ledger.tip = bincode::deserialize(&v).ok();
+            } else {
+                let wtx: WalletTx = bincode::deserialize(&v)?;
+                ledger.txs.insert(wtx.txid.clone(), wtx);
+            }
+        }
+
+        let scanned: HashSet<String> = match &ledger.tip {
+            Some(tip) => tip.addresses.iter().cloned().collect(),
+            None => HashSet::new(),
+        };
+        if !scanned.is_subset(owned) {
+            info!("wallet addresses removed, rebuild the ledger");
+            ledger.tip = None;
+        }
+
+        let best = chain.best_block_hash()?;
+        let mut blocks = Vec::new();
+        let mut hash = best.clone();
+        loop {
+            if hash.is_empty() {
+                if ledger.tip.is_some() {
+                    info!("indexed wallet tip left the chain, rebuild the ledger");
+                    ledger.tip = None;
+                }
+                break;
+            }
+            if ledger.tip.as_ref().map(|t| &t.hash) == Some(&hash) {
+                break;
+            }
+            let block = chain.get_block(&hash)?;
+            hash = block.get_prev_hash();
+            blocks.push(block);
+        }
+
+        let rebuild = ledger.tip.is_none();
+        let rescan = !rebuild && owned.iter().any(|address| !scanned.contains(address));
+        if rebuild {
+            ledger.txs.clear();
+        } else if rescan {
+            // the new addresses may have been paid before the indexed tip
+            info!("new wallet addresses, rescan the chain");
+            while !hash.is_empty() {
+                let block = chain.get_block(&hash)?;
+                hash = block.get_prev_hash();
+                blocks.push(block);
+            }
+        }
+
+        if !blocks.is_empty() || rebuild || rescan {
+            for block in blocks.iter().rev() {
+                for (pos, tx) in block.get_transaction().iter().enumerate() {
+                    ledger.add_tx(tx, Some(block), pos, owned);
+                }
+            }
+            let tip = LedgerTip {
+                hash: best,
+                height: blocks.first().map(|b| b.get_height()).unwrap_or(-1),
+                addresses: owned.iter().cloned().collect(),
+            };
+
+            let mut batch = sled::Batch::default();
+            if rebuild {
+                for item in tree.iter() {
+                    batch.remove(item?.0);
+                }
+            }
+            for wtx in ledger.txs.values() {
+                batch.insert(wtx.txid.as_bytes(), bincode::serialize(wtx)?);
+            }
+            batch.insert(TIP_ENTRY, bincode::serialize(&tip)?);
+            tree.apply_batch(batch)?;
+            db.flush()?;
+            ledger.tip = Some(tip);
+        }
+        drop(tree);
+        drop(db);
+
+        for tx in chain.mempool()? {
+            ledger.add_tx(&tx, None, 0, owned);
+        }
+        Ok(ledger)
+    }
+
+    /// add_tx indexes tx if it pays to or spends from owned, marking the wallet
+    /// outputs it spends. A mined transaction indexed already is indexed again,
+    /// so that a rescan adds what new addresses received and spent
+    fn add_tx(&mut self, tx: &Transaction, block: Option<&Block>, pos: usize, owned: &HashSet<String>) {
+        let indexed = match self.txs.get(&tx.id) {
+            Some(_) if block.is_none() => return,
+            Some(wtx) => wtx.received.clone(),
+            None => Vec::new(),
+        };
+        let mut sent = 0;
+        let mut all_inputs_known = !tx.is_coinbase();
+        let mut from = Vec::new();
+        if !tx.is_coinbase() {
+            for vin in &tx.vin {
+                let spent = self
+                    .txs
+                    .get_mut(&vin.txid)
+                    .and_then(|wtx| wtx.received.iter_mut().find(|out| out.vout == vin.vout));
+                match spent {
+                    Some(out) => {
+                        out.spent_by = Some(tx.id.clone());
+                        sent += out.value;
+                    }
+                    None => {
+                        all_inputs_known = false;
+                        let mut pub_key_hash = vin.pub_key.clone();
+                        hash_pub_key(&mut pub_key_hash);
+                        push_unique(&mut from, address_from_pub_key_hash(&pub_key_hash));
+                    }
+                }
+            }
+        }
+
+        let mut received = Vec::new();
+        let mut to = Vec::new();
+        for (n, out) in tx.vout.iter().enumerate() {
+            let address = address_from_pub_key_hash(&out.pub_key_hash);
+            if owned.contains(&address) {
+                let spent_by = indexed.iter().find(|out| out.vout == n as i32).and_then(|out| out.spent_by.clone());
+                received.push(WalletOutput {
+                    vout: n as i32,
+                    address,
+                    value: out.value,
+                    spent_by,
+                });
+            } else {
+                push_unique(&mut to, address);
+            }
+        }
+        if received.is_empty() && sent == 0 {
+            return;
+        }
+
+        let total_out: i32 = tx.vout.iter().map(|out| out.value).sum();
+        let wtx = WalletTx {
+            txid: tx.id.clone(),
+            coinbase: tx.is_coinbase(),
+            block_hash: block.map(|b| b.get_hash()),
+            height: block.map(|b| b.get_height()),
+            time: block.map(|b| b.get_timestamp()),
+            pos,
+            received,
+            sent,
+            fee: if all_inputs_known { Some(sent - total_out) } else { None },
+            counterparties: if sent > 0 { to } else { from },
+        };
+        self.txs.insert(wtx.txid.clone(), wtx);
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&WalletTx> {
+        self.txs.get(txid)
+    }
+
+    /// Transactions returns the wallet transactions oldest first, mempool last
+    pub fn transactions(&self) -> Vec<&WalletTx> {
+        let mut txs: Vec<&WalletTx> = self.txs.values().collect();
+        txs.sort_by_key(|wtx| (wtx.height.unwrap_or(i32::MAX), wtx.pos, wtx.txid.clone()));
+        txs
+    }
+
+    /// BestHeight is the height of the chain the ledger is indexed up to
+    pub fn best_height(&self) -> i32 {
+        self.tip.as_ref().map(|t| t.height).unwrap_or(-1)
+    }
+
+    /// Confirmations counts the blocks on top of the one holding wtx, 0 in the mempool
+    pub fn confirmations(&self, wtx: &WalletTx) -> i32 {
+        match wtx.height {
+            Some(height) => self.best_height() - height + 1,
+            None => 0,
+        }
+    }
+
+    /// Balance sums the unspent wallet outputs, counting as confirmed those with
+    /// at least minconf confirmations and as immature the coinbase outputs with
+    /// fewer than maturity
+    pub fn balance(&self, minconf: i32, maturity: i32) -> Balance {
+        let mut balance = Balance::default();
+        for wtx in self.txs.values() {
+            let confirmations = self.confirmations(wtx);
+            let value: i32 = wtx
+                .received
+                .iter()
+                .filter(|out| out.spent_by.is_none())
+                .map(|out| out.value)
+                .sum();
+            if wtx.coinbase && confirmations < maturity {
+                balance.immature += value;
+            } else if confirmations >= minconf {
+                balance.confirmed += value;
+            } else {
+                balance.unconfirmed += value;
+            }
+        }
+        balance
+    }
+}
+
+fn push_unique(list: &mut Vec<String>, address: String) {
+    if !list.contains(&address) {
+        list.push(address);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transaction::{TXInput, TXOutput, SEQUENCE_FINAL};
+
+    #[test]
+    fn test_add_tx() {
+        let mut ws = Wallets::new().unwrap();
+        let mine = ws.create_wallet().unwrap();
+        let other = ws.create_wallet().unwrap();
+        let mut owned: HashSet<String> = vec![mine.clone()].into_iter().collect();
+        let mut ledger = Ledger {
+            txs: HashMap::new(),
+            tip: None,
+        };
+
+        let coinbase = Transaction::new_coinbase(mine.clone(), String::from("ledger")).unwrap();
+        ledger.add_tx(&coinbase, None, 0, &owned);
+        let spend = Transaction {
+            id: String::from("spend"),
+            vin: vec![TXInput {
+                txid: coinbase.id.clone(),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: ws.get_wallet(&mine).unwrap().public_key.clone(),
+                sequence: SEQUENCE_FINAL,
+            }],
+            vout: vec![
+                TXOutput::new(4, other.clone()).unwrap(),
+                TXOutput::new(5, mine.clone()).unwrap(),
+            ],
+        };
+        ledger.add_tx(&spend, None, 0, &owned);
+
+        let wtx = ledger.get("spend").unwrap();
+        assert_eq!(wtx.category(), "send");
+        assert_eq!(wtx.amount(), -5);
+        assert_eq!(wtx.fee, Some(1));
+        assert_eq!(wtx.counterparties, vec![other.clone()]);
+        let funding = ledger.get(&coinbase.id).unwrap();
+        assert_eq!(funding.category(), "generate");
+        assert_eq!(funding.received[0].spent_by, Some(String::from("spend")));
+
+        let balance = ledger.balance(1, 100);
+        assert_eq!((balance.confirmed, balance.unconfirmed, balance.immature), (0, 5, 0));
+        assert_eq!(ledger.balance(0, 100).confirmed, 5);
+
+        // a rescan for a new address adds what it received to the indexed transaction
+        let block = Block::new_genesis_block(coinbase.clone());
+        owned.insert(other);
+        ledger.add_tx(&spend, Some(&block), 1, &owned);
+        let wtx = ledger.get("spend").unwrap();
+        assert_eq!(wtx.amount(), -1);
+        assert_eq!(wtx.received.len(), 2);
+        assert_eq!(wtx.height, Some(0));
+        let funding = ledger.get(&coinbase.id).unwrap();
+        assert_eq!(funding.received[0].spent_by, Some(String::from("spend")));
+    }
+}
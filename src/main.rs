@@ -1,11 +1,21 @@
+mod addrman;
 mod cli;
+mod coinselect;
+mod download;
+mod config;
 mod error;
 mod blockchain;
 mod  block;
 mod transaction;
-mod tx;
+mod hdwallet;
+mod inventory;
+mod ledger;
+mod mempool;
 mod wallet;
+mod miner;
 mod utxoset;
+mod psbt;
+mod rpc;
 mod server;
 
 use crate::cli::Cli;
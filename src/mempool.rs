@@ -0,0 +1,697 @@
+//! transactions waiting to be mined
+
+use super::*;
+use crate::addrman::now;
+use crate::transaction::*;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// INCREMENTAL_RELAY_FEE is the fee per 1000 bytes a replacement pays on top of
+/// the fees of the transactions it evicts
+pub const INCREMENTAL_RELAY_FEE: i32 = 1;
+/// MAX_REPLACEMENT_EVICTIONS bounds how many transactions one replacement may evict
+const MAX_REPLACEMENT_EVICTIONS: usize = 100;
+/// MAX_ANCESTORS bounds the chain of unconfirmed transactions a transaction depends on
+const MAX_ANCESTORS: usize = 25;
+/// DEFAULT_MAX_MEMPOOL_SIZE is the default bound of the mempool, in bytes
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 300_000_000;
+/// DEFAULT_MIN_RELAY_FEE is the default fee per 1000 bytes a transaction pays to
+/// be accepted, collected by the miner of the block including it
+pub const DEFAULT_MIN_RELAY_FEE: i32 = 1;
+/// ROLLING_FEE_HALFLIFE is how many seconds the minimum fee raised by a full
+/// mempool takes to fall by half
+const ROLLING_FEE_HALFLIFE: u64 = 12 * 60 * 60;
+
+/// MempoolEntry is a transaction accepted in the mempool with the fee it pays
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    /// fee is the value of the inputs minus the value of the outputs
+    pub fee: i32,
+    pub size: usize,
+    /// time is when the transaction entered the mempool, in seconds since the epoch
+    pub time: u64,
+}
+
+impl MempoolEntry {
+    /// New computes the fee of tx from the outputs it spends, keyed by (txid, vout)
+    pub fn new(tx: Transaction, prevouts: &HashMap<(String, i32), TXOutput>) -> Result<MempoolEntry> {
+        let mut fee: i64 = 0;
+        for vin in &tx.vin {
+            match prevouts.get(&(vin.txid.clone(), vin.vout)) {
+                Some(out) => fee += out.value as i64,
+                None => return Err(format_err!("output {}:{} spent by {} is unknown", vin.txid, vin.vout, tx.id)),
+            }
+        }
+        fee -= tx.vout.iter().map(|out| out.value as i64).sum::<i64>();
+        if fee < 0 {
+            return Err(format_err!("transaction {} spends more than its inputs", tx.id));
+        }
+        let size = tx.size()?;
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(MempoolEntry {
+            tx,
+            fee: fee as i32,
+            size,
+            time,
+        })
+    }
+
+    /// FeeRate is the fee paid per 1000 bytes
+    pub fn fee_rate(&self) -> f64 {
+        fee_rate(self.fee, self.size)
+    }
+}
+
+/// fee_rate is the fee per 1000 bytes of a transaction of size bytes
+pub fn fee_rate(fee: i32, size: usize) -> f64 {
+    fee as f64 * 1000.0 / size.max(1) as f64
+}
+
+/// fee_for_size is the fee a transaction of size bytes pays at fee_rate per 1000 bytes
+pub fn fee_for_size(fee_rate: i32, size: usize) -> i32 {
+    ((fee_rate as i64 * size as i64 + 999) / 1000) as i32
+}
+
+/// FeeRateKey orders fee rates in the mempool indexes
+#[derive(Clone, Copy, Debug)]
+struct FeeRateKey(f64);
+
+impl PartialEq for FeeRateKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRateKey {}
+
+impl PartialOrd for FeeRateKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FeeRateKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Mempool holds the valid transactions not mined yet
+pub struct Mempool {
+    entries: HashMap<String, MempoolEntry>,
+    /// spends maps every output spent by a mempool transaction to its id
+    spends: HashMap<(String, i32), String>,
+    /// descendants are the fee and size of each transaction together with the
+    /// mempool transactions spending its outputs, directly or not
+    descendants: HashMap<String, (i32, usize)>,
+    /// ancestor_packages are the fee and size of each transaction together with
+    /// the mempool transactions it spends outputs of, directly or not
+    ancestor_packages: HashMap<String, (i32, usize)>,
+    /// eviction orders the transactions by the score trim evicts by, lowest first
+    eviction: BTreeSet<(FeeRateKey, Reverse<String>)>,
+    /// size is the sum of the entries sizes, in bytes
+    size: usize,
+    max_size: usize,
+    min_relay_fee: i32,
+    /// rolling_min_fee is the fee rate raised when the mempool was last full, at
+    /// rolling_fee_time, it decays back to the minimum relay fee
+    rolling_min_fee: f64,
+    rolling_fee_time: u64,
+}
+
+/// MempoolInfo sums up the mempool state
+pub struct MempoolInfo {
+    pub count: usize,
+    pub size: usize,
+    pub max_size: usize,
+    pub min_fee_rate: f64,
+    pub min_relay_fee: i32,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(DEFAULT_MAX_MEMPOOL_SIZE, DEFAULT_MIN_RELAY_FEE)
+    }
+}
+
+impl Mempool {
+    /// New creates a mempool holding up to max_size bytes of transactions paying
+    /// at least min_relay_fee per 1000 bytes
+    pub fn new(max_size: usize, min_relay_fee: i32) -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+            spends: HashMap::new(),
+            descendants: HashMap::new(),
+            ancestor_packages: HashMap::new(),
+            eviction: BTreeSet::new(),
+            size: 0,
+            max_size,
+            min_relay_fee,
+            rolling_min_fee: 0.0,
+            rolling_fee_time: 0,
+        }
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn contains(&self, txid: &str) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    /// Transactions returns the mempool transactions by id
+    pub fn transactions(&self) -> HashMap<String, Transaction> {
+        self.entries
+            .iter()
+            .map(|(txid, entry)| (txid.clone(), entry.tx.clone()))
+            .collect()
+    }
+
+    pub fn info(&self) -> MempoolInfo {
+        MempoolInfo {
+            count: self.entries.len(),
+            size: self.size,
+            max_size: self.max_size,
+            min_fee_rate: self.min_fee_rate(),
+            min_relay_fee: self.min_relay_fee,
+        }
+    }
+
+    /// MinFeeRate is the fee rate per 1000 bytes a transaction pays to enter the
+    /// mempool: the minimum relay fee, raised for a while once the mempool was full
+    pub fn min_fee_rate(&self) -> f64 {
+        let elapsed = now().saturating_sub(self.rolling_fee_time) as f64;
+        let mut rolling = self.rolling_min_fee * 0.5f64.powf(elapsed / ROLLING_FEE_HALFLIFE as f64);
+        if rolling < INCREMENTAL_RELAY_FEE as f64 / 2.0 {
+            rolling = 0.0;
+        }
+        rolling.max(self.min_relay_fee as f64)
+    }
+
+    /// Entries returns the mempool entries, parents before their children
+    pub fn entries(&self) -> Vec<MempoolEntry> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_cached_key(|e| (self.ancestors(&e.tx).len(), e.time, e.tx.id.clone()));
+        entries.into_iter().cloned().collect()
+    }
+
+    /// Conflicts returns the mempool transactions spending an output tx spends
+    fn conflicts(&self, tx: &Transaction) -> HashSet<String> {
+        tx.vin
+            .iter()
+            .filter_map(|vin| self.spends.get(&(vin.txid.clone(), vin.vout)))
+            .cloned()
+            .collect()
+    }
+
+    /// Ancestors returns the mempool transactions tx spends outputs of, directly or not
+    pub fn ancestors(&self, tx: &Transaction) -> HashSet<String> {
+        let mut result = HashSet::new();
+        let mut queue: Vec<&Transaction> = vec![tx];
+        while let Some(tx) = queue.pop() {
+            for vin in &tx.vin {
+                if let Some(parent) = self.entries.get(&vin.txid) {
+                    if result.insert(vin.txid.clone()) {
+                        queue.push(&parent.tx);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// WithDescendants returns txids together with every mempool transaction
+    /// spending their outputs, directly or not
+    pub fn with_descendants(&self, txids: HashSet<String>) -> HashSet<String> {
+        let mut result = HashSet::new();
+        let mut queue: Vec<String> = txids.into_iter().collect();
+        while let Some(txid) = queue.pop() {
+            if !result.insert(txid.clone()) {
+                continue;
+            }
+            if let Some(entry) = self.entries.get(&txid) {
+                for vout in 0..entry.tx.vout.len() as i32 {
+                    if let Some(child) = self.spends.get(&(txid.clone(), vout)) {
+                        queue.push(child.clone());
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Add inserts entry. Transactions spending the same outputs are replaced when
+    /// they all signal replace-by-fee and entry pays more, both in absolute fee and
+    /// in fee rate. When the mempool is then over its maximum size the lowest fee
+    /// rate packages are evicted. Returns the ids of the removed transactions
+    pub fn add(&mut self, entry: MempoolEntry) -> Result<Vec<String>> {
+        if self.contains(&entry.tx.id) {
+            return Err(format_err!("transaction already in mempool"));
+        }
+        let min_fee_rate = self.min_fee_rate();
+        if entry.fee_rate() < min_fee_rate {
+            return Err(format_err!(
+                "fee rate {:.1} is below the mempool minimum of {:.1}",
+                entry.fee_rate(),
+                min_fee_rate
+            ));
+        }
+        let ancestors = self.ancestors(&entry.tx);
+        if ancestors.len() > MAX_ANCESTORS {
+            return Err(format_err!(
+                "transaction has {} unconfirmed ancestors, at most {} are allowed",
+                ancestors.len(),
+                MAX_ANCESTORS
+            ));
+        }
+        let conflicts = self.conflicts(&entry.tx);
+        let mut evicted = Vec::new();
+        if !conflicts.is_empty() {
+            for txid in &conflicts {
+                let conflict = &self.entries[txid];
+                if !conflict.tx.signals_rbf() {
+                    return Err(format_err!("transaction conflicts with {} which is not replaceable", txid));
+                }
+                if entry.fee_rate() <= conflict.fee_rate() {
+                    return Err(format_err!(
+                        "replacement fee rate {:.1} is not above {:.1} of {}",
+                        entry.fee_rate(),
+                        conflict.fee_rate(),
+                        txid
+                    ));
+                }
+            }
+
+            let replaced = self.with_descendants(conflicts);
+            if replaced.len() > MAX_REPLACEMENT_EVICTIONS {
+                return Err(format_err!(
+                    "replacement would evict {} transactions, at most {} are allowed",
+                    replaced.len(),
+                    MAX_REPLACEMENT_EVICTIONS
+                ));
+            }
+            if entry.tx.vin.iter().any(|vin| replaced.contains(&vin.txid)) {
+                return Err(format_err!("replacement spends an output of a transaction it replaces"));
+            }
+            let replaced_fee: i32 = replaced.iter().map(|txid| self.entries[txid].fee).sum();
+            let required = replaced_fee + fee_for_size(INCREMENTAL_RELAY_FEE, entry.size);
+            if entry.fee < required {
+                return Err(format_err!(
+                    "replacement pays a fee of {}, at least {} is required",
+                    entry.fee,
+                    required
+                ));
+            }
+            self.remove_entries(&replaced);
+            evicted.extend(replaced);
+        }
+
+        let txid = entry.tx.id.clone();
+        self.insert_entry(entry);
+
+        evicted.extend(self.trim());
+        if !self.contains(&txid) {
+            return Err(format_err!("mempool full"));
+        }
+        Ok(evicted)
+    }
+
+    /// Trim evicts packages until the mempool fits in its maximum size, each time
+    /// the transaction with the lowest score and its descendants. The score is the
+    /// best of its own fee rate and the one of its descendants package, so that a
+    /// parent is not evicted for a child paying less. The minimum fee rate rises
+    /// above the evicted ones. Returns the evicted ids
+    fn trim(&mut self) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.size > self.max_size {
+            let (score, txid) = match self.eviction.iter().next() {
+                Some((score, Reverse(txid))) => (score.0, txid.clone()),
+                None => break,
+            };
+            self.rolling_min_fee = self.min_fee_rate().max(score + INCREMENTAL_RELAY_FEE as f64);
+            self.rolling_fee_time = now();
+            let package = self.with_descendants(HashSet::from([txid]));
+            self.remove_entries(&package);
+            let mut package: Vec<String> = package.into_iter().collect();
+            package.sort();
+            evicted.extend(package);
+        }
+        evicted
+    }
+
+    /// eviction_key is where txid sits in the eviction order: its trim score,
+    /// the highest id first among equal scores
+    fn eviction_key(&self, txid: &str) -> (FeeRateKey, Reverse<String>) {
+        let (package_fee, package_size) = self.descendants[txid];
+        let score = self.entries[txid].fee_rate().max(fee_rate(package_fee, package_size));
+        (FeeRateKey(score), Reverse(txid.to_string()))
+    }
+
+    /// update_descendants adds the fee and size of a descendant to the package
+    /// of txid, or takes them off
+    fn update_descendants(&mut self, txid: &str, fee: i32, size: usize, add: bool) {
+        self.eviction.remove(&self.eviction_key(txid));
+        if let Some((package_fee, package_size)) = self.descendants.get_mut(txid) {
+            if add {
+                *package_fee += fee;
+                *package_size += size;
+            } else {
+                *package_fee -= fee;
+                *package_size -= size;
+            }
+        }
+        self.eviction.insert(self.eviction_key(txid));
+    }
+
+    /// SelectPackages picks the transactions to mine, up to max_size bytes. Each
+    /// transaction is ranked by the fee rate of its package, itself with its
+    /// unconfirmed ancestors not picked yet, so that a child paying a high fee
+    /// brings its parents along. Parents come before their children
+    pub fn select_packages(&self, max_size: usize) -> Vec<Transaction> {
+        // the ancestor packages less the ancestors picked so far, only the
+        // descendants of a picked package change
+        let mut packages = self.ancestor_packages.clone();
+        let mut queue: BinaryHeap<(FeeRateKey, Reverse<String>)> = packages
+            .iter()
+            .map(|(txid, (fee, size))| (FeeRateKey(fee_rate(*fee, *size)), Reverse(txid.clone())))
+            .collect();
+        let mut selected = Vec::new();
+        let mut included: HashSet<String> = HashSet::new();
+        let mut skipped: HashSet<String> = HashSet::new();
+        let mut size = 0;
+        while let Some((rate, Reverse(txid))) = queue.pop() {
+            if included.contains(&txid) || skipped.contains(&txid) {
+                continue;
+            }
+            let (package_fee, package_size) = packages[&txid];
+            if rate != FeeRateKey(fee_rate(package_fee, package_size)) {
+                // ranked before some of its ancestors were picked
+                continue;
+            }
+            if size + package_size > max_size {
+                // the package does not fit, a smaller one may
+                skipped.insert(txid);
+                continue;
+            }
+            size += package_size;
+
+            let mut package: Vec<String> = self
+                .ancestors(&self.entries[&txid].tx)
+                .into_iter()
+                .filter(|a| !included.contains(a))
+                .collect();
+            package.push(txid);
+            // an ancestor has fewer unconfirmed ancestors than its descendants
+            package.sort_by_cached_key(|t| (self.ancestors(&self.entries[t].tx).len(), t.clone()));
+            for t in package {
+                let entry = &self.entries[&t];
+                for d in self.with_descendants(HashSet::from([t.clone()])) {
+                    if d == t || included.contains(&d) {
+                        continue;
+                    }
+                    if let Some((fee, size)) = packages.get_mut(&d) {
+                        *fee -= entry.fee;
+                        *size -= entry.size;
+                        queue.push((FeeRateKey(fee_rate(*fee, *size)), Reverse(d)));
+                    }
+                }
+                selected.push(entry.tx.clone());
+                included.insert(t);
+            }
+        }
+        selected
+    }
+
+    /// RemoveForBlock drops the transactions mined in a block, and those spending
+    /// the same outputs with their descendants, which can no longer be mined
+    pub fn remove_for_block(&mut self, txs: &[Transaction]) {
+        for tx in txs {
+            self.remove_entry(&tx.id);
+            if tx.is_coinbase() {
+                continue;
+            }
+            let conflicts = self.with_descendants(self.conflicts(tx));
+            self.remove_entries(&conflicts);
+        }
+    }
+
+    /// Expire drops the transactions which entered the mempool before cutoff, in
+    /// seconds since the epoch, with their descendants. Returns the dropped ids
+    pub fn expire(&mut self, cutoff: u64) -> Vec<String> {
+        let expired: HashSet<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.time < cutoff)
+            .map(|(txid, _)| txid.clone())
+            .collect();
+        let removed = self.with_descendants(expired);
+        self.remove_entries(&removed);
+        let mut removed: Vec<String> = removed.into_iter().collect();
+        removed.sort();
+        removed
+    }
+
+    /// insert_entry adds entry and its fee and size to the packages of its
+    /// ancestors, and sums up its own ancestor package. Transactions already
+    /// spending its outputs gain ancestors too
+    fn insert_entry(&mut self, entry: MempoolEntry) {
+        let txid = entry.tx.id.clone();
+        let children: HashSet<String> = (0..entry.tx.vout.len() as i32)
+            .filter_map(|vout| self.spends.get(&(txid.clone(), vout)))
+            .cloned()
+            .collect();
+        let orphans: Vec<(String, HashSet<String>)> = self
+            .with_descendants(children)
+            .into_iter()
+            .map(|d| {
+                let ancestors = self.ancestors(&self.entries[&d].tx);
+                (d, ancestors)
+            })
+            .collect();
+
+        for vin in &entry.tx.vin {
+            self.spends.insert((vin.txid.clone(), vin.vout), txid.clone());
+        }
+        self.size += entry.size;
+        self.descendants.insert(txid.clone(), (entry.fee, entry.size));
+        let ancestors = self.ancestors(&entry.tx);
+        let (fee, size) = (entry.fee, entry.size);
+        let package = ancestors.iter().fold((fee, size), |(f, s), a| {
+            (f + self.entries[a].fee, s + self.entries[a].size)
+        });
+        self.ancestor_packages.insert(txid.clone(), package);
+        self.entries.insert(txid.clone(), entry);
+        self.eviction.insert(self.eviction_key(&txid));
+
+        for ancestor in ancestors {
+            self.update_descendants(&ancestor, fee, size, true);
+        }
+        for (d, before) in orphans {
+            let entry = &self.entries[&d];
+            let (fee, size) = (entry.fee, entry.size);
+            for ancestor in self.ancestors(&entry.tx).difference(&before) {
+                self.update_descendants(ancestor, fee, size, true);
+                let gained = &self.entries[ancestor];
+                if let Some((package_fee, package_size)) = self.ancestor_packages.get_mut(&d) {
+                    *package_fee += gained.fee;
+                    *package_size += gained.size;
+                }
+            }
+        }
+    }
+
+    /// remove_entries removes txids, which hold the descendants of each of them.
+    /// Children go before their parents, so that each removal finds every
+    /// ancestor whose package it leaves
+    fn remove_entries(&mut self, txids: &HashSet<String>) {
+        let mut order: Vec<&String> = txids.iter().filter(|t| self.entries.contains_key(*t)).collect();
+        order.sort_by_cached_key(|t| Reverse(self.ancestors(&self.entries[*t].tx).len()));
+        for txid in order {
+            self.remove_entry(txid);
+        }
+    }
+
+    fn remove_entry(&mut self, txid: &str) {
+        let (fee, size, ancestors) = match self.entries.get(txid) {
+            Some(entry) => (entry.fee, entry.size, self.ancestors(&entry.tx)),
+            None => return,
+        };
+        for ancestor in ancestors {
+            self.update_descendants(&ancestor, fee, size, false);
+        }
+        for descendant in self.with_descendants(HashSet::from([txid.to_string()])) {
+            if let Some((package_fee, package_size)) = self.ancestor_packages.get_mut(&descendant) {
+                if descendant != txid {
+                    *package_fee -= fee;
+                    *package_size -= size;
+                }
+            }
+        }
+        self.eviction.remove(&self.eviction_key(txid));
+        self.descendants.remove(txid);
+        self.ancestor_packages.remove(txid);
+        if let Some(entry) = self.entries.remove(txid) {
+            self.size -= entry.size;
+            for vin in &entry.tx.vin {
+                self.spends.remove(&(vin.txid.clone(), vin.vout));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(id: &str, prevout: (&str, i32), fee: i32, sequence: u32) -> MempoolEntry {
+        MempoolEntry {
+            tx: Transaction {
+                id: id.to_string(),
+                vin: vec![TXInput {
+                    txid: prevout.0.to_string(),
+                    vout: prevout.1,
+                    signature: Vec::new(),
+                    pub_key: Vec::new(),
+                    sequence,
+                }],
+                vout: Vec::new(),
+            },
+            fee,
+            size: 300,
+            time: 0,
+        }
+    }
+
+    #[test]
+    fn test_replace_by_fee() {
+        let mut mempool = Mempool::default();
+        mempool.add(entry("a", ("funding", 0), 2, SEQUENCE_RBF)).unwrap();
+        mempool.add(entry("final", ("funding", 1), 2, SEQUENCE_FINAL)).unwrap();
+        assert!(mempool.add(entry("a", ("funding", 0), 2, SEQUENCE_RBF)).is_err());
+
+        // not replaceable
+        assert!(mempool.add(entry("b", ("funding", 1), 10, SEQUENCE_RBF)).is_err());
+        // the fee must cover the replaced fee plus the incremental relay fee
+        assert!(mempool.add(entry("b", ("funding", 0), 2, SEQUENCE_RBF)).is_err());
+        assert!(mempool.add(entry("b", ("funding", 0), 3, SEQUENCE_RBF)).is_ok());
+        assert!(!mempool.contains("a"));
+
+        mempool.remove_for_block(&[entry("mined", ("funding", 1), 0, SEQUENCE_FINAL).tx]);
+        assert!(!mempool.contains("final"));
+        assert_eq!(mempool.transactions().len(), 1);
+    }
+
+    #[test]
+    fn test_select_packages() {
+        let mut mempool = Mempool::default();
+        mempool.add(entry("parent", ("funding", 0), 1, SEQUENCE_FINAL)).unwrap();
+        mempool.add(entry("other", ("funding", 1), 2, SEQUENCE_FINAL)).unwrap();
+        mempool.add(entry("child", ("parent", 0), 9, SEQUENCE_FINAL)).unwrap();
+        let ids = |txs: Vec<Transaction>| txs.into_iter().map(|tx| tx.id).collect::<Vec<_>>();
+
+        // the child pays for its parent, the package fits in 600 bytes
+        assert_eq!(ids(mempool.select_packages(600)), vec!["parent", "child"]);
+        assert_eq!(ids(mempool.select_packages(900)), vec!["parent", "child", "other"]);
+        assert_eq!(ids(mempool.select_packages(400)), vec!["other"]);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut mempool = Mempool::default();
+        let mut old = entry("old", ("funding", 0), 1, SEQUENCE_FINAL);
+        old.time = 100;
+        old.tx.vout.push(TXOutput {
+            value: 1,
+            pub_key_hash: Vec::new(),
+        });
+        let mut child = entry("child", ("old", 0), 1, SEQUENCE_FINAL);
+        child.time = 300;
+        let mut recent = entry("recent", ("funding", 1), 1, SEQUENCE_FINAL);
+        recent.time = 200;
+        mempool.add(child.clone()).unwrap();
+        mempool.add(recent).unwrap();
+        mempool.add(old).unwrap();
+
+        let ids = |entries: Vec<MempoolEntry>| entries.into_iter().map(|e| e.tx.id).collect::<Vec<_>>();
+        assert_eq!(ids(mempool.entries()), vec!["old", "recent", "child"]);
+
+        // the child goes with its expired parent
+        assert_eq!(mempool.expire(150), vec!["child", "old"]);
+        assert_eq!(ids(mempool.entries()), vec!["recent"]);
+    }
+
+    #[test]
+    fn test_trim() {
+        let mut mempool = Mempool::new(900, 1);
+        assert!(mempool.add(entry("free", ("funding", 0), 0, SEQUENCE_FINAL)).is_err());
+
+        let mut parent = entry("parent", ("funding", 0), 3, SEQUENCE_FINAL);
+        parent.tx.vout.push(TXOutput {
+            value: 1,
+            pub_key_hash: Vec::new(),
+        });
+        mempool.add(parent).unwrap();
+        mempool.add(entry("child", ("parent", 0), 1, SEQUENCE_FINAL)).unwrap();
+        mempool.add(entry("other", ("funding", 1), 2, SEQUENCE_FINAL)).unwrap();
+        assert_eq!(mempool.info().size, 900);
+
+        // the low paying child goes first, the fee to enter rises above its rate
+        let evicted = mempool.add(entry("rich", ("funding", 2), 6, SEQUENCE_FINAL)).unwrap();
+        assert_eq!(evicted, vec!["child"]);
+        assert!((mempool.min_fee_rate() - 4.33).abs() < 0.01);
+        assert!(mempool.add(entry("cheap", ("funding", 3), 1, SEQUENCE_FINAL)).is_err());
+
+        // evicted right away when paying the least
+        let mut low = entry("low", ("funding", 3), 2, SEQUENCE_FINAL);
+        low.size = 400;
+        assert!(mempool.add(low).is_err());
+        assert!(!mempool.contains("low"));
+        assert_eq!(mempool.info().count, 3);
+    }
+
+    #[test]
+    fn test_packages() {
+        let check = |mempool: &Mempool| {
+            for txid in mempool.entries.keys() {
+                let package = mempool.with_descendants(HashSet::from([txid.clone()]));
+                let fee: i32 = package.iter().map(|t| mempool.entries[t].fee).sum();
+                let size: usize = package.iter().map(|t| mempool.entries[t].size).sum();
+                assert_eq!(mempool.descendants[txid], (fee, size), "package of {}", txid);
+                let mut package = mempool.ancestors(&mempool.entries[txid].tx);
+                package.insert(txid.clone());
+                let fee: i32 = package.iter().map(|t| mempool.entries[t].fee).sum();
+                let size: usize = package.iter().map(|t| mempool.entries[t].size).sum();
+                assert_eq!(mempool.ancestor_packages[txid], (fee, size), "ancestors of {}", txid);
+            }
+            assert_eq!(mempool.eviction.len(), mempool.entries.len());
+        };
+        let chained = |id: &str, prevout: (&str, i32), fee: i32| {
+            let mut e = entry(id, prevout, fee, SEQUENCE_FINAL);
+            e.tx.vout.push(TXOutput {
+                value: 1,
+                pub_key_hash: Vec::new(),
+            });
+            e
+        };
+
+        // the grandchild arrives first and joins the packages once linked
+        let mut mempool = Mempool::default();
+        mempool.add(chained("c", ("b", 0), 3)).unwrap();
+        mempool.add(chained("a", ("funding", 0), 1)).unwrap();
+        mempool.add(chained("b", ("a", 0), 2)).unwrap();
+        mempool.add(chained("d", ("c", 0), 4)).unwrap();
+        check(&mempool);
+        assert_eq!(mempool.descendants["a"], (10, 1200));
+
+        mempool.remove_for_block(&[chained("a", ("funding", 0), 1).tx]);
+        check(&mempool);
+        mempool.expire(1);
+        assert_eq!(mempool.info().count, 0);
+        check(&mempool);
+    }
+}
@@ -0,0 +1,140 @@
+//! background block miner
+
+use super::*;
+use crate::block::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{error, info};
+
+const HASHRATE_INTERVAL: Duration = Duration::from_secs(10);
+/// pause before retrying after the template could not be built or submitted
+const ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// MiningContext gives the miner access to the node's chain and mempool
+pub trait MiningContext: Send + 'static {
+    /// BlockTemplate builds the next block to mine, None when there is nothing to mine
+    fn block_template(&self) -> Result<Option<Block>>;
+    /// SubmitBlock hands a solved block back, returns false if it became stale
+    fn submit_block(&self, block: Block) -> Result<bool>;
+}
+
+/// Miner mines blocks in a background thread and restarts its work whenever
+/// the chain tip or the mempool changes
+#[derive(Clone)]
+pub struct Miner {
+    inner: Arc<MinerInner>,
+}
+
+struct MinerInner {
+    /// generation is bumped on every change that invalidates the current template
+    generation: Mutex<u64>,
+    changed: Condvar,
+    hashes: AtomicU64,
+    threads: usize,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Miner {
+        Miner {
+            inner: Arc::new(MinerInner {
+                generation: Mutex::new(0),
+                changed: Condvar::new(),
+                hashes: AtomicU64::new(0),
+                threads,
+            }),
+        }
+    }
+
+    /// Start spawns the mining and hashrate reporting threads
+    pub fn start<C: MiningContext>(&self, ctx: C) {
+        let miner = self.clone();
+        thread::spawn(move || miner.mining_loop(ctx));
+        let miner = self.clone();
+        thread::spawn(move || miner.report_hashrate());
+    }
+
+    /// NewTip tells the miner a new best block arrived
+    pub fn new_tip(&self) {
+        info!("new tip, restart mining");
+        self.interrupt();
+    }
+
+    /// MempoolChanged tells the miner the mempool content changed
+    pub fn mempool_changed(&self) {
+        self.interrupt();
+    }
+
+    fn interrupt(&self) {
+        *self.inner.generation.lock().unwrap() += 1;
+        self.inner.changed.notify_all();
+    }
+
+    fn generation(&self) -> u64 {
+        *self.inner.generation.lock().unwrap()
+    }
+
+    fn wait_for_change(&self, generation: u64) {
+        let guard = self
+            .inner
+            .changed
+            .wait_while(self.inner.generation.lock().unwrap(), |g| *g == generation)
+            .unwrap();
+        drop(guard);
+    }
+
+    fn mining_loop<C: MiningContext>(&self, ctx: C) {
+        loop {
+            let generation = self.generation();
+            let mut block = match ctx.block_template() {
+                Ok(Some(b)) => b,
+                Ok(None) => {
+                    self.wait_for_change(generation);
+                    continue;
+                }
+                Err(e) => {
+                    error!("failed to build block template: {}", e);
+                    thread::sleep(ERROR_BACKOFF);
+                    continue;
+                }
+            };
+
+            let abort = || self.generation() != generation;
+            let solved = match block.mine(self.inner.threads, abort, &self.inner.hashes) {
+                Ok(solved) => solved,
+                Err(e) => {
+                    error!("mining failed: {}", e);
+                    thread::sleep(ERROR_BACKOFF);
+                    continue;
+                }
+            };
+            if !solved {
+                info!("block template outdated, restart mining");
+                continue;
+            }
+
+            match ctx.submit_block(block.clone()) {
+                Ok(true) => info!("mined block {} at height {}", block.get_hash(), block.get_height()),
+                Ok(false) => info!("mined block {} is stale", block.get_hash()),
+                Err(e) => {
+                    error!("failed to submit block: {}", e);
+                    thread::sleep(ERROR_BACKOFF);
+                }
+            }
+        }
+    }
+
+    fn report_hashrate(&self) {
+        let mut last = Instant::now();
+        loop {
+            thread::sleep(HASHRATE_INTERVAL);
+            let hashes = self.inner.hashes.swap(0, Ordering::Relaxed);
+            let elapsed = last.elapsed().as_secs_f64();
+            last = Instant::now();
+            if hashes > 0 {
+                info!("hashrate: {:.0} H/s", hashes as f64 / elapsed);
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! partially signed transactions exchanged between signers
+
+use super::*;
+use crate::transaction::*;
+use crate::wallet::*;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use crypto::ed25519;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// PSBT_MAGIC prefixes every serialized Psbt
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+/// Psbt is a transaction under construction together with the outputs it
+/// spends and the signatures gathered so far
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Psbt {
+    /// tx is the unsigned transaction, its inputs carry no signature nor public key
+    pub tx: Transaction,
+    pub inputs: Vec<PsbtInput>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PsbtInput {
+    /// prevout is the output the input spends, needed to sign offline
+    pub prevout: Option<TXOutput>,
+    /// partial_sigs maps a public key to its signature of the input
+    pub partial_sigs: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// final_pub_key and final_signature are set once the input is finalized
+    pub final_pub_key: Vec<u8>,
+    pub final_signature: Vec<u8>,
+}
+
+impl PsbtInput {
+    fn is_finalized(&self) -> bool {
+        !self.final_signature.is_empty()
+    }
+}
+
+impl Psbt {
+    /// New wraps tx, dropping any signature it already carries
+    pub fn new(tx: &Transaction) -> Result<Psbt> {
+        if tx.is_coinbase() {
+            return Err(format_err!("a coinbase transaction cannot be signed"));
+        }
+        let mut tx = tx.clone();
+        for vin in &mut tx.vin {
+            vin.signature.clear();
+            vin.pub_key.clear();
+        }
+        tx.id = tx.compute_id()?;
+        let inputs = vec![PsbtInput::default(); tx.vin.len()];
+        Ok(Psbt { tx, inputs })
+    }
+
+    /// Update records the outputs spent by the inputs, keyed by (txid, vout)
+    pub fn update(&mut self, prevouts: &HashMap<(String, i32), TXOutput>) {
+        for (vin, input) in self.tx.vin.iter().zip(self.inputs.iter_mut()) {
+            if input.prevout.is_none() {
+                input.prevout = prevouts.get(&(vin.txid.clone(), vin.vout)).cloned();
+            }
+        }
+    }
+
+    /// Sign adds a signature for every input whose prevout belongs to one of
+    /// the wallets, returns how many inputs were signed
+    pub fn sign(&mut self, wallets: &Wallets) -> Result<usize> {
+        wallets.ensure_unlocked()?;
+        let mut signed = 0;
+        for in_id in 0..self.inputs.len() {
+            let prevout = match &self.inputs[in_id].prevout {
+                Some(out) => out.clone(),
+                None => continue,
+            };
+            let wallet = match wallets.get_wallet(&address_from_pub_key_hash(&prevout.pub_key_hash)) {
+                Some(w) => w,
+                None => continue,
+            };
+            let hash = self.tx.signature_hash(in_id, &prevout.pub_key_hash)?;
+            let signature = ed25519::signature(hash.as_bytes(), &wallet.secret_key);
+            self.inputs[in_id]
+                .partial_sigs
+                .insert(wallet.public_key.clone(), signature.to_vec());
+            signed += 1;
+        }
+        Ok(signed)
+    }
+
+    /// Combine merges the prevouts and signatures of other, which must wrap the
+    /// same unsigned transaction
+    pub fn combine(&mut self, other: &Psbt) -> Result<()> {
+        if self.tx.id != other.tx.id {
+            return Err(format_err!("cannot combine PSBTs of different transactions"));
+        }
+        for (input, other) in self.inputs.iter_mut().zip(other.inputs.iter()) {
+            if input.prevout.is_none() {
+                input.prevout = other.prevout.clone();
+            }
+            for (pub_key, signature) in &other.partial_sigs {
+                input.partial_sigs.insert(pub_key.clone(), signature.clone());
+            }
+            if !input.is_finalized() && other.is_finalized() {
+                input.final_pub_key = other.final_pub_key.clone();
+                input.final_signature = other.final_signature.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Finalize picks for each input a valid signature from the key locking
+    /// its prevout, returns true when every input is finalized
+    pub fn finalize(&mut self) -> Result<bool> {
+        for in_id in 0..self.inputs.len() {
+            if self.inputs[in_id].is_finalized() {
+                continue;
+            }
+            let prevout = match &self.inputs[in_id].prevout {
+                Some(out) => out.clone(),
+                None => continue,
+            };
+            let hash = self.tx.signature_hash(in_id, &prevout.pub_key_hash)?;
+            let input = &mut self.inputs[in_id];
+            let found = input.partial_sigs.iter().find(|(pub_key, signature)| {
+                let mut pub_key_hash = pub_key.to_vec();
+                hash_pub_key(&mut pub_key_hash);
+                pub_key_hash == prevout.pub_key_hash
+                    && ed25519::verify(hash.as_bytes(), pub_key, signature)
+            });
+            if let Some((pub_key, signature)) = found {
+                input.final_pub_key = pub_key.clone();
+                input.final_signature = signature.clone();
+                input.partial_sigs.clear();
+            }
+        }
+        Ok(self.inputs.iter().all(|i| i.is_finalized()))
+    }
+
+    /// Extract returns the signed transaction once every input is finalized
+    pub fn extract(&self) -> Result<Transaction> {
+        let mut tx = self.tx.clone();
+        for (vin, input) in tx.vin.iter_mut().zip(self.inputs.iter()) {
+            if !input.is_finalized() {
+                return Err(format_err!("input {}:{} is not finalized", vin.txid, vin.vout));
+            }
+            vin.pub_key = input.final_pub_key.clone();
+            vin.signature = input.final_signature.clone();
+        }
+        tx.id = tx.compute_id()?;
+        Ok(tx)
+    }
+
+    pub fn to_base64(&self) -> Result<String> {
+        let mut data = PSBT_MAGIC.to_vec();
+        data.extend(bincode::serialize(self)?);
+        Ok(BASE64.encode(data))
+    }
+
+    pub fn from_base64(data: &str) -> Result<Psbt> {
+        let data = BASE64.decode(data.trim())?;
+        if !data.starts_with(PSBT_MAGIC) {
+            return Err(format_err!("not a PSBT"));
+        }
+        let psbt: Psbt = bincode::deserialize(&data[PSBT_MAGIC.len()..])?;
+        if psbt.inputs.len() != psbt.tx.vin.len() {
+            return Err(format_err!("PSBT inputs do not match the transaction"));
+        }
+        Ok(psbt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_combine_finalize() {
+        let mut ws1 = Wallets::new().unwrap();
+        let mut ws2 = Wallets::new().unwrap();
+        let a1 = ws1.create_wallet().unwrap();
+        let a2 = ws2.create_wallet().unwrap();
+        let prev1 = Transaction::new_coinbase(a1.clone(), String::from("psbt 1")).unwrap();
+        let prev2 = Transaction::new_coinbase(a2, String::from("psbt 2")).unwrap();
+
+        let tx = Transaction::new_raw(&[(prev1.id.clone(), 0), (prev2.id.clone(), 0)], &[(a1, 15)]).unwrap();
+        let mut prevouts = HashMap::new();
+        prevouts.insert((prev1.id.clone(), 0), prev1.vout[0].clone());
+        prevouts.insert((prev2.id.clone(), 0), prev2.vout[0].clone());
+        let mut psbt = Psbt::new(&tx).unwrap();
+        psbt.update(&prevouts);
+
+        let mut p1 = Psbt::from_base64(&psbt.to_base64().unwrap()).unwrap();
+        let mut p2 = psbt.clone();
+        assert_eq!(p1.sign(&ws1).unwrap(), 1);
+        assert_eq!(p2.sign(&ws2).unwrap(), 1);
+        assert!(!p1.clone().finalize().unwrap());
+        assert!(p1.extract().is_err());
+
+        p1.combine(&p2).unwrap();
+        assert!(p1.finalize().unwrap());
+        let signed = p1.extract().unwrap();
+
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev1.id.clone(), prev1);
+        prev_txs.insert(prev2.id.clone(), prev2);
+        assert!(signed.verify(prev_txs).unwrap());
+    }
+}
@@ -0,0 +1,630 @@
+//! JSON-RPC endpoint of the node
+//!
+//! A small HTTP/1.1 server that answers JSON-RPC requests posted by local
+//! clients, and the matching client used by the CLI while a node is running.
+//!
+//! Every request must carry HTTP basic auth. On startup the node writes a
+//! random password to the cookie file in the data directory, which local
+//! clients read; a user and password from the config are accepted as well.
+
+use super::*;
+use crate::block::*;
+use crate::coinselect::DEFAULT_STRATEGY;
+use crate::config::Config;
+use crate::server::Server;
+use crate::transaction::*;
+use crate::wallet::{address_from_pub_key_hash, Wallets};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bitcoincash_addr::Address;
+use failure::format_err;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde_json::{json, Value};
+use std::io::prelude::*;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+use log::{error, info, warn};
+
+pub const COOKIE_FILE: &str = "data/.cookie";
+const COOKIE_USER: &str = "__cookie__";
+/// delay answering a request with bad credentials to slow down guessing
+const AUTH_FAILURE_DELAY: Duration = Duration::from_millis(250);
+
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+/// largest request body accepted
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+const RPC_PARSE_ERROR: i64 = -32700;
+const RPC_METHOD_NOT_FOUND: i64 = -32601;
+const RPC_INVALID_PARAMS: i64 = -32602;
+const RPC_MISC_ERROR: i64 = -1;
+const RPC_NOT_FOUND: i64 = -5;
+const RPC_VERIFY_REJECTED: i64 = -26;
+const RPC_WALLET_UNLOCK_NEEDED: i64 = -13;
+
+/// RpcError is the error object of a JSON-RPC response
+#[derive(Debug)]
+pub struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<failure::Error> for RpcError {
+    fn from(e: failure::Error) -> Self {
+        RpcError::new(RPC_MISC_ERROR, &e.to_string())
+    }
+}
+
+type RpcResult = std::result::Result<Value, RpcError>;
+
+/// RpcServer answers JSON-RPC calls against a running node
+pub struct RpcServer {
+    server: Server,
+    /// credentials holds the accepted `user:password` pairs
+    credentials: Vec<String>,
+}
+
+impl RpcServer {
+    /// New creates the endpoint and writes a fresh cookie file
+    pub fn new(server: Server, credentials: Option<(String, String)>) -> Result<RpcServer> {
+        let mut accepted = vec![write_cookie()?];
+        if let Some((user, password)) = credentials {
+            accepted.push(format!("{}:{}", user, password));
+        }
+        Ok(RpcServer {
+            server,
+            credentials: accepted,
+        })
+    }
+
+    /// Serve handles requests on listener one at a time
+    pub fn serve(&self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("failed to accept RPC connection: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.handle_connection(stream) {
+                error!("failed to handle RPC request: {}", e);
+            }
+        }
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let (authorization, body) = match read_http_request(&mut stream) {
+            Ok(request) => request,
+            Err(e) => return write_http_response(&mut stream, 400, &e.to_string()),
+        };
+
+        if !self.is_authorized(authorization.as_deref()) {
+            warn!("RPC request with incorrect credentials from {}", stream.peer_addr()?);
+            thread::sleep(AUTH_FAILURE_DELAY);
+            return write_http_response(&mut stream, 401, "");
+        }
+
+        let request: Value = match serde_json::from_slice(&body) {
+            Ok(v) => v,
+            Err(e) => {
+                let response = error_response(Value::Null, RpcError::new(RPC_PARSE_ERROR, &e.to_string()));
+                return write_http_response(&mut stream, 200, &response.to_string());
+            }
+        };
+
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = request.get("method").and_then(|m| m.as_str()).unwrap_or("");
+        let params = match request.get("params") {
+            Some(Value::Array(p)) => p.clone(),
+            _ => Vec::new(),
+        };
+        info!("RPC call: {} {:?}", method, params);
+
+        let response = match self.dispatch(method, &params) {
+            Ok(result) => json!({ "result": result, "error": null, "id": id }),
+            Err(e) => error_response(id, e),
+        };
+        write_http_response(&mut stream, 200, &response.to_string())
+    }
+
+    /// is_authorized checks a basic auth header against the accepted credentials
+    fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        let encoded = match authorization.and_then(|a| a.strip_prefix("Basic ")) {
+            Some(e) => e.trim(),
+            None => return false,
+        };
+        let decoded = match BASE64.decode(encoded) {
+            Ok(d) => d,
+            Err(_) => return false,
+        };
+        self.credentials
+            .iter()
+            .any(|c| constant_time_eq(c.as_bytes(), &decoded))
+    }
+
+    fn dispatch(&self, method: &str, params: &[Value]) -> RpcResult {
+        match method {
+            "getblockcount" => Ok(json!(self.server.get_best_height()?)),
+            "getbestblockhash" => Ok(json!(self.server.get_best_block_hash())),
+            "getblock" => self.get_block(params),
+            "getrawmempool" => Ok(json!(self.server.get_mempool().keys().collect::<Vec<_>>())),
+            "getrawtransaction" => self.get_raw_transaction(params),
+            "sendrawtransaction" => self.send_raw_transaction(params),
+            "decoderawtransaction" => self.decode_raw_transaction(params),
+            "getbalance" => self.get_balance(params),
+            "getmempoolinfo" => self.get_mempool_info(),
+            "getmempoolentry" => self.get_mempool_entry(params),
+            "getpeerinfo" => self.get_peer_info(),
+            "walletpassphrase" => self.wallet_passphrase(params),
+            "walletlock" => {
+                self.server.wallet_lock();
+                Ok(Value::Null)
+            }
+            "getnewaddress" => self.get_new_address(),
+            "sendfrom" => self.send_from(params),
+            "sendtoaddress" => self.send_to_address(params),
+            "sendmany" => self.send_many(params),
+            "bumpfee" => self.bump_fee(params),
+            _ => Err(RpcError::new(RPC_METHOD_NOT_FOUND, "Method not found")),
+        }
+    }
+
+    fn get_block(&self, params: &[Value]) -> RpcResult {
+        let hash = str_param(params, 0)?;
+        let verbose = params.get(1).and_then(|v| v.as_bool()).unwrap_or(true);
+        let block = self
+            .server
+            .get_block(hash)
+            .map_err(|_| RpcError::new(RPC_NOT_FOUND, "Block not found"))?;
+        if !verbose {
+            return Ok(json!(hex::encode(bincode::serialize(&block).map_err(failure::Error::from)?)));
+        }
+        let best_height = self.server.get_best_height()?;
+        Ok(block_to_json(&block, best_height))
+    }
+
+    fn get_raw_transaction(&self, params: &[Value]) -> RpcResult {
+        let txid = str_param(params, 0)?;
+        let verbose = params.get(1).and_then(|v| v.as_bool()).unwrap_or(false);
+
+        let (tx, block) = match self.server.get_mempool_tx(txid) {
+            Some(tx) => (tx, None),
+            None => match self.server.find_confirmed_tx(txid) {
+                Some((tx, block)) => (tx, Some(block)),
+                None => return Err(RpcError::new(RPC_NOT_FOUND, "No such transaction")),
+            },
+        };
+
+        if !verbose {
+            return Ok(json!(tx.to_hex()?));
+        }
+        let mut result = tx_to_json(&tx);
+        if let Some(block) = block {
+            let best_height = self.server.get_best_height()?;
+            result["blockhash"] = json!(block.get_hash());
+            result["confirmations"] = json!(best_height - block.get_height() + 1);
+        } else {
+            result["confirmations"] = json!(0);
+        }
+        Ok(result)
+    }
+
+    fn send_raw_transaction(&self, params: &[Value]) -> RpcResult {
+        let tx = Transaction::from_hex(str_param(params, 0)?)
+            .map_err(|e| RpcError::new(RPC_INVALID_PARAMS, &format!("TX decode failed: {}", e)))?;
+        let txid = tx.id.clone();
+        self.server
+            .submit_transaction(tx)
+            .map_err(|e| RpcError::new(RPC_VERIFY_REJECTED, &e.to_string()))?;
+        Ok(json!(txid))
+    }
+
+    fn decode_raw_transaction(&self, params: &[Value]) -> RpcResult {
+        let tx = Transaction::from_hex(str_param(params, 0)?)
+            .map_err(|e| RpcError::new(RPC_INVALID_PARAMS, &format!("TX decode failed: {}", e)))?;
+        Ok(tx_to_json(&tx))
+    }
+
+    fn get_balance(&self, params: &[Value]) -> RpcResult {
+        let address = str_param(params, 0)?;
+        let pub_key_hash = Address::decode(address)
+            .map_err(|_| RpcError::new(RPC_INVALID_PARAMS, "Invalid address"))?
+            .body;
+        Ok(json!(self.server.get_balance(&pub_key_hash)?))
+    }
+
+    fn get_mempool_info(&self) -> RpcResult {
+        let info = self.server.get_mempool_info();
+        Ok(json!({
+            "size": info.count,
+            "bytes": info.size,
+            "maxmempool": info.max_size,
+            "mempoolminfee": info.min_fee_rate,
+            "minrelaytxfee": info.min_relay_fee,
+        }))
+    }
+
+    fn get_mempool_entry(&self, params: &[Value]) -> RpcResult {
+        let txid = str_param(params, 0)?;
+        let entry = match self.server.get_mempool_entry(txid) {
+            Some(entry) => entry,
+            None => return Err(RpcError::new(RPC_NOT_FOUND, "Transaction not in mempool")),
+        };
+        Ok(json!({
+            "fee": entry.fee,
+            "size": entry.size,
+            "feerate": entry.fee_rate(),
+            "time": entry.time,
+            "replaceable": entry.tx.signals_rbf(),
+        }))
+    }
+
+    fn wallet_passphrase(&self, params: &[Value]) -> RpcResult {
+        let passphrase = str_param(params, 0)?;
+        let timeout = params
+            .get(1)
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| RpcError::new(RPC_INVALID_PARAMS, "missing or invalid timeout"))?;
+        self.server
+            .wallet_passphrase(passphrase, Duration::from_secs(timeout))
+            .map_err(|e| RpcError::new(RPC_MISC_ERROR, &e.to_string()))?;
+        Ok(Value::Null)
+    }
+
+    /// unlocked_wallet loads the wallets with their secret keys, which an
+    /// encrypted wallet only has while walletpassphrase keeps it unlocked
+    fn unlocked_wallet(&self) -> std::result::Result<Wallets, RpcError> {
+        let wallets = self.server.open_wallet()?;
+        if wallets.is_locked() {
+            return Err(RpcError::new(
+                RPC_WALLET_UNLOCK_NEEDED,
+                "wallet is locked, unlock it first with walletpassphrase",
+            ));
+        }
+        Ok(wallets)
+    }
+
+    fn get_new_address(&self) -> RpcResult {
+        let mut wallets = self.unlocked_wallet()?;
+        let address = wallets.create_wallet()?;
+        wallets.save_all()?;
+        Ok(json!(address))
+    }
+
+    fn send_from(&self, params: &[Value]) -> RpcResult {
+        let from = str_param(params, 0)?;
+        let to = str_param(params, 1)?;
+        let amount = amount_param(params, 2)?;
+        let wallets = self.unlocked_wallet()?;
+        Ok(json!(self.server.send_from(&wallets, from, to, amount)?))
+    }
+
+    fn send_to_address(&self, params: &[Value]) -> RpcResult {
+        let to = str_param(params, 0)?;
+        let amount = amount_param(params, 1)?;
+        let strategy = params.get(2).and_then(|v| v.as_str()).unwrap_or(DEFAULT_STRATEGY);
+        let mut wallets = self.unlocked_wallet()?;
+        Ok(json!(self.server.send_many(&mut wallets, &[(to.to_string(), amount)], strategy)?))
+    }
+
+    /// send_many pays a list of [address, amount] pairs, in the order given
+    fn send_many(&self, params: &[Value]) -> RpcResult {
+        let pairs = params
+            .first()
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| RpcError::new(RPC_INVALID_PARAMS, "expected a list of [address, amount] pairs"))?;
+        let mut outputs = Vec::new();
+        for pair in pairs {
+            let pair = pair.as_array().map(|p| p.as_slice()).unwrap_or_default();
+            if pair.len() != 2 {
+                return Err(RpcError::new(RPC_INVALID_PARAMS, "expected a list of [address, amount] pairs"));
+            }
+            outputs.push((str_param(pair, 0)?.to_string(), amount_param(pair, 1)?));
+        }
+        let strategy = params.get(1).and_then(|v| v.as_str()).unwrap_or(DEFAULT_STRATEGY);
+        let mut wallets = self.unlocked_wallet()?;
+        Ok(json!(self.server.send_many(&mut wallets, &outputs, strategy)?))
+    }
+
+    fn bump_fee(&self, params: &[Value]) -> RpcResult {
+        let txid = str_param(params, 0)?;
+        let fee_rate = match params.get(1) {
+            None | Some(Value::Null) => None,
+            Some(_) => Some(amount_param(params, 1)?),
+        };
+        let wallets = self.unlocked_wallet()?;
+        let (new_txid, old_fee, new_fee) = self.server.bump_fee(&wallets, txid, fee_rate)?;
+        Ok(json!({ "txid": new_txid, "origfee": old_fee, "fee": new_fee }))
+    }
+
+    fn get_peer_info(&self) -> RpcResult {
+        let peers: Vec<Value> = self
+            .server
+            .get_peer_info()
+            .into_iter()
+            .map(|(addr, info)| match info {
+                Some(i) => json!({
+                    "addr": addr,
+                    "lastseen": i.last_seen,
+                    "lastsuccess": i.last_success,
+                    "lasttried": i.last_tried,
+                    "attempts": i.attempts,
+                }),
+                None => json!({ "addr": addr }),
+            })
+            .collect();
+        Ok(json!(peers))
+    }
+}
+
+/// block_to_json describes a block the way getblock reports it
+pub fn block_to_json(block: &Block, best_height: i32) -> Value {
+    let txids: Vec<String> = block.get_transaction().iter().map(|tx| tx.id.clone()).collect();
+    json!({
+        "hash": block.get_hash(),
+        "height": block.get_height(),
+        "confirmations": best_height - block.get_height() + 1,
+        "previousblockhash": block.get_prev_hash(),
+        "time": block.get_timestamp() as u64,
+        "nonce": block.get_nonce(),
+        "tx": txids,
+    })
+}
+
+/// tx_to_json decodes a transaction into readable JSON
+pub fn tx_to_json(tx: &Transaction) -> Value {
+    let vin: Vec<Value> = tx
+        .vin
+        .iter()
+        .map(|vin| {
+            json!({
+                "txid": vin.txid,
+                "vout": vin.vout,
+                "pubkey": hex::encode(&vin.pub_key),
+                "signature": hex::encode(&vin.signature),
+                "sequence": vin.sequence,
+            })
+        })
+        .collect();
+    let vout: Vec<Value> = tx
+        .vout
+        .iter()
+        .enumerate()
+        .map(|(n, out)| {
+            json!({
+                "n": n,
+                "value": out.value,
+                "address": address_from_pub_key_hash(&out.pub_key_hash),
+            })
+        })
+        .collect();
+    json!({
+        "txid": tx.id,
+        "coinbase": tx.is_coinbase(),
+        "replaceable": tx.signals_rbf(),
+        "vin": vin,
+        "vout": vout,
+    })
+}
+
+fn str_param(params: &[Value], idx: usize) -> std::result::Result<&str, RpcError> {
+    params
+        .get(idx)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| RpcError::new(RPC_INVALID_PARAMS, &format!("missing string parameter {}", idx)))
+}
+
+/// amount_param reads a positive amount that fits a transaction output
+fn amount_param(params: &[Value], idx: usize) -> std::result::Result<i32, RpcError> {
+    match params.get(idx).and_then(|v| v.as_i64()) {
+        Some(amount) if amount > 0 && amount <= i32::MAX as i64 => Ok(amount as i32),
+        _ => Err(RpcError::new(RPC_INVALID_PARAMS, &format!("invalid amount parameter {}", idx))),
+    }
+}
+
+fn error_response(id: Value, e: RpcError) -> Value {
+    json!({
+        "result": null,
+        "error": { "code": e.code, "message": e.message },
+        "id": id,
+    })
+}
+
+/// write_cookie stores a new random password for local clients, readable by the owner only
+fn write_cookie() -> Result<String> {
+    let mut secret: [u8; 32] = [0; 32];
+    OsRng.fill_bytes(&mut secret);
+    let cookie = format!("{}:{}", COOKIE_USER, hex::encode(secret));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(COOKIE_FILE)?;
+    file.write_all(cookie.as_bytes())?;
+    Ok(cookie)
+}
+
+/// read_credentials returns the `user:password` a client should send, preferring
+/// the configured ones over the cookie
+fn read_credentials(config: &Config) -> Result<String> {
+    if let Some((user, password)) = config.rpc_credentials() {
+        return Ok(format!("{}:{}", user, password));
+    }
+    std::fs::read_to_string(COOKIE_FILE)
+        .map(|c| c.trim().to_string())
+        .map_err(|e| format_err!("could not read RPC cookie {}: {}", COOKIE_FILE, e))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// read_http_request reads a POST request and returns its authorization header and body
+fn read_http_request(stream: &mut TcpStream) -> Result<(Option<String>, Vec<u8>)> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    if !request_line.starts_with("POST ") {
+        return Err(format_err!("only POST is supported"));
+    }
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(format_err!("unexpected end of headers"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            } else if name.trim().eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        return Err(format_err!("request body too large"));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok((authorization, body))
+}
+
+fn write_http_response(stream: &mut TcpStream, status: u16, body: &str) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        _ => "Error",
+    };
+    let auth_header = if status == 401 {
+        "WWW-Authenticate: Basic realm=\"jsonrpc\"\r\n"
+    } else {
+        ""
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        auth_header,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+/// is_node_running checks whether a node answers on the RPC address
+pub fn is_node_running(addr: &str) -> bool {
+    let addrs = match addr.to_socket_addrs() {
+        Ok(a) => a,
+        Err(_) => return false,
+    };
+    for sock in addrs {
+        if TcpStream::connect_timeout(&sock, CONNECT_TIMEOUT).is_ok() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Call invokes method on the RPC endpoint of the node described by config
+pub fn call(config: &Config, method: &str, params: Vec<Value>) -> Result<Value> {
+    let addr = &config.rpc_address;
+    let credentials = read_credentials(config)?;
+    let body = json!({ "jsonrpc": "1.0", "id": "cli", "method": method, "params": params }).to_string();
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+    let request = format!(
+        "POST / HTTP/1.1\r\nHost: {}\r\nAuthorization: Basic {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        addr,
+        BASE64.encode(credentials),
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = match response.split_once("\r\n\r\n") {
+        Some(parts) => parts,
+        None => return Err(format_err!("malformed RPC response")),
+    };
+    if head.starts_with("HTTP/1.1 401") {
+        return Err(format_err!("RPC authorization failed, check rpc_user and rpc_password"));
+    }
+    let reply: Value = serde_json::from_str(body)
+        .map_err(|_| format_err!("RPC request failed: {}", body))?;
+    if let Some(e) = reply.get("error").filter(|e| !e.is_null()) {
+        return Err(format_err!(
+            "RPC error {}: {}",
+            e["code"],
+            e["message"].as_str().unwrap_or("")
+        ));
+    }
+    Ok(reply["result"].clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_http_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let body = r#"{"method":"getblockcount"}"#;
+            let request = format!(
+                "POST / HTTP/1.1\r\nAuthorization: Basic {}\r\nContent-Length: {}\r\n\r\n{}",
+                BASE64.encode("user:pass"),
+                body.len(),
+                body
+            );
+            stream.write_all(request.as_bytes()).unwrap();
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let (authorization, body) = read_http_request(&mut stream).unwrap();
+        client.join().unwrap();
+        assert_eq!(authorization.unwrap(), format!("Basic {}", BASE64.encode("user:pass")));
+        assert_eq!(body, br#"{"method":"getblockcount"}"#.to_vec());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"__cookie__:abc", b"__cookie__:abc"));
+        assert!(!constant_time_eq(b"__cookie__:abc", b"__cookie__:abd"));
+        assert!(!constant_time_eq(b"__cookie__:abc", b"__cookie__:ab"));
+    }
+}
@@ -1,29 +1,49 @@
 //! server of Blockchain
 
 use super::*;
+use crate::addrman::{self, AddrInfo, AddrMan};
 use crate::block::*;
+use crate::coinselect;
+use crate::config::Config;
+use crate::download::BlockDownload;
+use crate::inventory::PeerInventory;
+use crate::mempool::{self, Mempool, MempoolEntry, MempoolInfo, INCREMENTAL_RELAY_FEE};
+use crate::miner::{Miner, MiningContext};
+use crate::rpc::RpcServer;
 use crate::transaction::*;
 use crate::utxoset::*;
+use crate::wallet::{address_from_pub_key_hash, wipe, Wallets};
 use bincode::{deserialize, serialize};
 use failure::format_err;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{sync_channel, Receiver, TrySendError};
 use std::sync::*;
 use std::thread;
-use std::time::Duration;
-use log::{debug, info};
+use std::time::{Duration, Instant};
+use log::{debug, error, info, warn};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Message {
-    Addr(Vec<String>),
+    Addr(Addrmsg),
     Version(Versionmsg),
     Tx(Txmsg),
     GetData(GetDatamsg),
     GetBlock(GetBlocksmsg),
     Inv(Invmsg),
     Block(Blockmsg),
+    CmpctBlock(CmpctBlockmsg),
+    GetBlockTxn(GetBlockTxnmsg),
+    BlockTxn(BlockTxnmsg),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Addrmsg {
+    addr_from: String,
+    addrs: Vec<(String, u64)>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -32,6 +52,26 @@ struct Blockmsg {
     block: Block,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CmpctBlockmsg {
+    addr_from: String,
+    block: CompactBlock,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetBlockTxnmsg {
+    addr_from: String,
+    block_hash: String,
+    indexes: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct BlockTxnmsg {
+    addr_from: String,
+    block_hash: String,
+    transactions: Vec<Transaction>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GetBlocksmsg {
     addr_from: String,
@@ -65,183 +105,887 @@ struct Versionmsg {
 }
 
 pub struct Server {
+    listen_addresses: Vec<String>,
     node_address: String,
     mining_address: String,
-    inner: Arc<Mutex<ServerInner>>,
+    bootstrap_nodes: Vec<String>,
+    network_threads: usize,
+    mine_empty_blocks: bool,
+    rpc_bind: Option<String>,
+    rpc_credentials: Option<(String, String)>,
+    persist_mempool: bool,
+    mempool_expiry: Duration,
+    inner: Arc<ServerInner>,
 }
 
+/// ServerInner is the state shared by the connection workers and the miner,
+/// each part behind its own lock so that no handler waits on unrelated work
 struct ServerInner {
+    peers: Mutex<PeerState>,
+    utxo: Mutex<UTXOSet>,
+    block_download: Mutex<BlockDownload>,
+    mempool: Mutex<Mempool>,
+    /// partial_blocks are the compact blocks waiting for their missing
+    /// transactions, with the peer which sent them and when
+    partial_blocks: Mutex<HashMap<String, (PartialBlock, String, Instant)>>,
+    /// requested_txs are the transactions asked to a peer and when
+    requested_txs: Mutex<HashMap<String, Instant>>,
+    miner: Miner,
+    /// wallet_unlock is the key of the wallet while walletpassphrase keeps it unlocked
+    wallet_unlock: Mutex<Option<WalletUnlock>>,
+}
+
+/// WalletUnlock is the key of an unlocked wallet, wiped when dropped
+struct WalletUnlock {
+    key: Vec<u8>,
+    until: Instant,
+}
+
+impl Drop for WalletUnlock {
+    fn drop(&mut self) {
+        wipe(&mut self.key);
+    }
+}
+
+struct PeerState {
     known_nodes: HashSet<String>,
-    utxo: UTXOSet,
-    blocks_in_transit: Vec<String>,
-    mempool: HashMap<String, Transaction>,
+    addrman: AddrMan,
+    addr_tokens: HashMap<String, (f64, Instant)>,
+    /// inventory tracks the transactions each peer knows about
+    inventory: HashMap<String, PeerInventory>,
 }
 
-const KNOWN_NODE1: &str = "localhost:3000";
 const CMD_LEN: usize = 12;
+const PEERS_FILE: &str = "data/peers";
+const MEMPOOL_FILE: &str = "data/mempool";
+/// number of peers the node tries to keep talking to
+const TARGET_PEERS: usize = 8;
+/// addr messages with more entries than this are ignored
+const MAX_ADDR_PER_MSG: usize = 1000;
+/// number of addresses sent in one gossip round
+const ADDR_GOSSIP_SIZE: usize = 50;
+const ADDR_GOSSIP_INTERVAL: Duration = Duration::from_secs(60);
+/// inv messages with more entries than this are ignored
+const MAX_INV_PER_MSG: usize = 50_000;
+/// how often the relay thread looks for announcements due
+const INV_TICK: Duration = Duration::from_millis(100);
+/// a block requested for longer is asked to another peer
+const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+const BLOCK_DOWNLOAD_TICK: Duration = Duration::from_secs(1);
+/// addresses a peer may send per second once its burst allowance is used up
+const ADDR_RATE: f64 = 0.1;
+const ADDR_BURST: f64 = MAX_ADDR_PER_MSG as f64;
+/// accepted connections waiting for a free worker, more are dropped
+const CONNECTION_QUEUE: usize = 256;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// largest message accepted from a peer
+const MAX_MESSAGE_SIZE: u64 = 32 * 1024 * 1024;
 const VERSION: i32 = 1;
+/// compact blocks rebuilt at once, the oldest is downloaded in full past it
+const MAX_PARTIAL_BLOCKS: usize = 16;
+/// a compact block still missing transactions after this is downloaded in full
+const PARTIAL_BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+/// a transaction requested for longer may be asked to the next peer announcing it
+const TX_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// MAX_BLOCK_TX_SIZE bounds the size of the mempool transactions packed in a mined block
+const MAX_BLOCK_TX_SIZE: usize = 1_000_000;
 
 impl Server {
-    pub fn new(port: &str, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
+    pub fn new(port: &str, miner_address: &str, mut utxo: UTXOSet, config: &Config) -> Result<Server> {
+        utxo.blockchain.coinbase_maturity = config.coinbase_maturity;
+        let bootstrap_nodes = config.bootstrap_nodes();
         let mut node_set = HashSet::new();
-        node_set.insert(String::from(KNOWN_NODE1));
+        for node in &bootstrap_nodes {
+            node_set.insert(node.clone());
+        }
         Ok(Server {
-            node_address: String::from("localhost:") + port,
+            listen_addresses: config.listen_addresses(port),
+            node_address: config.advertised_address(port),
             mining_address: miner_address.to_string(),
-            inner: Arc::new(Mutex::new(ServerInner {
-                known_nodes: node_set,
-                utxo,
-                blocks_in_transit: Vec::new(),
-                mempool: HashMap::new(),
-            })),
+            bootstrap_nodes,
+            network_threads: config.network_threads.max(1),
+            mine_empty_blocks: config.mine_empty_blocks,
+            rpc_bind: config.rpc_bind(),
+            rpc_credentials: config.rpc_credentials(),
+            persist_mempool: config.persist_mempool,
+            mempool_expiry: Duration::from_secs(config.mempool_expiry * 60 * 60),
+            inner: Arc::new(ServerInner {
+                peers: Mutex::new(PeerState {
+                    known_nodes: node_set,
+                    addrman: AddrMan::new(PEERS_FILE)?,
+                    addr_tokens: HashMap::new(),
+                    inventory: HashMap::new(),
+                }),
+                utxo: Mutex::new(utxo),
+                block_download: Mutex::new(BlockDownload::new(BLOCK_DOWNLOAD_TIMEOUT)),
+                mempool: Mutex::new(Mempool::new(config.max_mempool_size, config.min_relay_fee)),
+                partial_blocks: Mutex::new(HashMap::new()),
+                requested_txs: Mutex::new(HashMap::new()),
+                miner: Miner::new(config.mining_threads),
+                wallet_unlock: Mutex::new(None),
+            }),
         })
     }
 
     pub fn start_server(&self) -> Result<()> {
-        let server1 = Server {
-            node_address: self.node_address.clone(),
-            mining_address: self.mining_address.clone(),
-            inner: Arc::clone(&self.inner),
-        };
+        let server1 = self.clone_handle();
         info!(
-            "Start server at {}, minning address: {}",
-            &self.node_address, &self.mining_address
+            "Start server at {:?} as {}, minning address: {}",
+            &self.listen_addresses, &self.node_address, &self.mining_address
         );
 
+        if self.persist_mempool {
+            if let Err(e) = self.load_mempool() {
+                error!("failed to load mempool: {}", e);
+            }
+        }
+        let server = self.clone_handle();
+        ctrlc::set_handler(move || server.shutdown())?;
+
         thread::spawn(move || {
             thread::sleep(Duration::from_millis(1000));
             if server1.get_best_height()? == -1 {
                 server1.request_blocks()
             } else {
-                server1.send_version(KNOWN_NODE1)
+                for node in &server1.bootstrap_nodes {
+                    server1.send_version(node)?;
+                }
+                Ok(())
             }
         });
 
-        let listener = TcpListener::bind(&self.node_address).unwrap();
-        info!("Server listen...");
+        let server2 = self.clone_handle();
+        thread::spawn(move || loop {
+            thread::sleep(ADDR_GOSSIP_INTERVAL);
+            if let Err(e) = server2.maintain_peers() {
+                error!("peer maintenance failed: {}", e);
+            }
+            server2.expire_mempool();
+        });
 
-        for stream in listener.incoming() {
-            let stream = stream?;
-            let server1 = Server {
-                node_address: self.node_address.clone(),
-                mining_address: self.mining_address.clone(),
-                inner: Arc::clone(&self.inner),
-            };
-            thread::spawn(move || server1.handle_connection(stream));
+        let server3 = self.clone_handle();
+        thread::spawn(move || loop {
+            thread::sleep(INV_TICK);
+            server3.announce_transactions();
+        });
+
+        let server4 = self.clone_handle();
+        thread::spawn(move || loop {
+            thread::sleep(BLOCK_DOWNLOAD_TICK);
+            if let Err(e) = server4.expire_partial_blocks() {
+                error!("compact block expiry failed: {}", e);
+            }
+            if let Err(e) = server4.request_next_block() {
+                error!("block download failed: {}", e);
+            }
+        });
+
+        if !self.mining_address.is_empty() {
+            self.inner.miner.start(self.clone_handle());
+        }
+
+        if let Some(addr) = &self.rpc_bind {
+            match TcpListener::bind(addr) {
+                Ok(listener) => {
+                    info!("RPC listen on {}...", addr);
+                    let rpc = RpcServer::new(self.clone_handle(), self.rpc_credentials.clone())?;
+                    thread::spawn(move || rpc.serve(listener));
+                }
+                Err(e) => error!("failed to bind RPC to {}: {}", addr, e),
+            }
         }
 
+        let (queue, incoming) = sync_channel::<TcpStream>(CONNECTION_QUEUE);
+        let incoming = Arc::new(Mutex::new(incoming));
+        for _ in 0..self.network_threads {
+            let server1 = self.clone_handle();
+            let incoming = Arc::clone(&incoming);
+            thread::spawn(move || server1.worker_loop(incoming));
+        }
+
+        let mut listeners = Vec::new();
+        for addr in &self.listen_addresses {
+            let listener = TcpListener::bind(addr)
+                .map_err(|e| format_err!("failed to bind {}: {}", addr, e))?;
+            info!("Server listen on {}...", addr);
+            let queue = queue.clone();
+            listeners.push(thread::spawn(move || accept_loop(listener, queue)));
+        }
+
+        for l in listeners {
+            l.join().unwrap()?;
+        }
         Ok(())
     }
 
-    pub fn send_transaction(tx: &Transaction, utxoset: UTXOSet) -> Result<()> {
-        let server = Server::new("7000", "", utxoset)?;
-        server.send_tx(KNOWN_NODE1, tx)?;
+    /// worker_loop handles queued connections one at a time
+    fn worker_loop(&self, incoming: Arc<Mutex<Receiver<TcpStream>>>) {
+        loop {
+            let stream = match incoming.lock().unwrap().recv() {
+                Ok(s) => s,
+                Err(_) => return,
+            };
+            // a bug a peer can trigger must not take the worker down with it
+            match panic::catch_unwind(AssertUnwindSafe(|| self.handle_connection(stream))) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("failed to handle connection: {}", e),
+                Err(_) => error!("connection handler panicked"),
+            }
+        }
+    }
+
+
+    pub fn send_transaction(tx: &Transaction, utxoset: UTXOSet, config: &Config) -> Result<()> {
+        let server = Server::new("7000", "", utxoset, config)?;
+        for node in &server.bootstrap_nodes {
+            server.send_tx(node, tx)?;
+        }
         Ok(())
     }
 
+    /// shutdown flushes the address book, and the mempool when it persists, then exits
+    fn shutdown(&self) {
+        if let Err(e) = self.inner.peers.lock().unwrap().addrman.save_all() {
+            error!("failed to save peer addresses: {}", e);
+        }
+        if self.persist_mempool {
+            if let Err(e) = self.save_mempool() {
+                error!("failed to save mempool: {}", e);
+            }
+        }
+        std::process::exit(0);
+    }
+
     /* ------------------- inner halp functions ----------------------------------*/
 
-    fn remove_node(&self, addr: &str) {
-        self.inner.lock().unwrap().known_nodes.remove(addr);
+    fn clone_handle(&self) -> Server {
+        Server {
+            listen_addresses: self.listen_addresses.clone(),
+            node_address: self.node_address.clone(),
+            mining_address: self.mining_address.clone(),
+            bootstrap_nodes: self.bootstrap_nodes.clone(),
+            network_threads: self.network_threads,
+            mine_empty_blocks: self.mine_empty_blocks,
+            rpc_bind: self.rpc_bind.clone(),
+            rpc_credentials: self.rpc_credentials.clone(),
+            persist_mempool: self.persist_mempool,
+            mempool_expiry: self.mempool_expiry,
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// connect_failed records a failed connection to addr, the peer is only
+    /// dropped once the address book gives up on it
+    fn connect_failed(&self, addr: &str) {
+        let mut peers = self.inner.peers.lock().unwrap();
+        if peers.addrman.attempt(addr) {
+            info!("drop unreachable peer: {}", addr);
+            peers.known_nodes.remove(addr);
+            peers.inventory.remove(addr);
+        }
+    }
+
+    /// is_self reports whether addr is one of our own listen or advertised addresses
+    fn is_self(&self, addr: &str) -> bool {
+        addr == self.node_address || self.listen_addresses.iter().any(|a| a == addr)
+    }
+
+    fn mark_good(&self, addr: &str) {
+        self.inner.peers.lock().unwrap().addrman.good(addr);
+    }
+
+    /// take_addr_tokens charges the peer for count addresses, returns how many it may send
+    fn take_addr_tokens(&self, peer: &str, count: usize) -> usize {
+        let mut peers = self.inner.peers.lock().unwrap();
+        let now = Instant::now();
+        let bucket = peers
+            .addr_tokens
+            .entry(addrman::group(peer))
+            .or_insert((ADDR_BURST, now));
+        let elapsed = now.duration_since(bucket.1).as_secs_f64();
+        bucket.0 = (bucket.0 + elapsed * ADDR_RATE).min(ADDR_BURST);
+        bucket.1 = now;
+        let allowed = (bucket.0.floor() as usize).min(count);
+        bucket.0 -= allowed as f64;
+        allowed
     }
 
     fn add_nodes(&self, addr: &str) {
         self.inner
+            .peers
             .lock()
             .unwrap()
             .known_nodes
             .insert(String::from(addr));
     }
 
-    fn get_known_nodes(&self) -> HashSet<String> {
-        self.inner.lock().unwrap().known_nodes.clone()
+    pub(crate) fn get_known_nodes(&self) -> HashSet<String> {
+        self.inner.peers.lock().unwrap().known_nodes.clone()
+    }
+
+    /// get_peer_info returns what the address book knows about each current peer
+    pub(crate) fn get_peer_info(&self) -> Vec<(String, Option<AddrInfo>)> {
+        let peers = self.inner.peers.lock().unwrap();
+        let mut info: Vec<(String, Option<AddrInfo>)> = peers
+            .known_nodes
+            .iter()
+            .map(|n| (n.clone(), peers.addrman.get(n).cloned()))
+            .collect();
+        info.sort_by(|a, b| a.0.cmp(&b.0));
+        info
+    }
+
+    /// maintain_peers tops up the peer set from the address book, gossips
+    /// addresses to the current peers and persists the book
+    fn maintain_peers(&self) -> Result<()> {
+        let (candidates, peers) = {
+            let peers = self.inner.peers.lock().unwrap();
+            let missing = TARGET_PEERS.saturating_sub(peers.known_nodes.len());
+            let candidates: Vec<String> = peers
+                .addrman
+                .select(TARGET_PEERS * 2)
+                .into_iter()
+                .filter(|a| !self.is_self(a) && !peers.known_nodes.contains(a))
+                .take(missing)
+                .collect();
+            (candidates, peers.known_nodes.clone())
+        };
+
+        for node in candidates {
+            self.add_nodes(&node);
+            self.send_version(&node)?;
+        }
+        for node in peers {
+            self.send_addr(&node)?;
+        }
+
+        self.inner.peers.lock().unwrap().addrman.save_all()
+    }
+
+    /// add_known_inventory records that the peer known as addr_from has the transactions
+    /// ids, the claim is ignored unless addr_from is a known peer and the message
+    /// came from its address
+    fn add_known_inventory(&self, addr_from: &str, peer: &SocketAddr, ids: &[String]) {
+        if !self.node_is_known(addr_from) || !resolves_to(addr_from, peer) {
+            debug!("ignore inventory of unknown peer: {} ({})", addr_from, peer);
+            return;
+        }
+        let mut peers = self.inner.peers.lock().unwrap();
+        let inv = peers.inventory.entry(addr_from.to_string()).or_insert_with(PeerInventory::new);
+        for id in ids {
+            inv.add_known(id);
+        }
+    }
+
+    /// request_txs returns the ids which are not asked to a peer already and
+    /// marks them as requested
+    fn request_txs<'a>(&self, ids: &[&'a String]) -> Vec<&'a String> {
+        let now = Instant::now();
+        let mut requested = self.inner.requested_txs.lock().unwrap();
+        requested.retain(|_, at| now.duration_since(*at) < TX_REQUEST_TIMEOUT);
+        let mut wanted = Vec::new();
+        for id in ids {
+            if !requested.contains_key(id.as_str()) {
+                requested.insert(id.to_string(), now);
+                wanted.push(*id);
+            }
+        }
+        wanted
+    }
+
+    /// queue_announcement queues txid for every peer but from which does not know it yet
+    fn queue_announcement(&self, txid: &str, from: &str) {
+        let mut peers = self.inner.peers.lock().unwrap();
+        let nodes: Vec<String> = peers
+            .known_nodes
+            .iter()
+            .filter(|n| !self.is_self(n) && *n != from)
+            .cloned()
+            .collect();
+        for node in nodes {
+            peers.inventory.entry(node).or_insert_with(PeerInventory::new).push(txid);
+        }
+    }
+
+    /// announce_transactions sends the announcements due, one inv per peer, leaving
+    /// out the transactions which left the mempool meanwhile
+    fn announce_transactions(&self) {
+        let now = Instant::now();
+        let due: Vec<(String, Vec<String>)> = {
+            let mut peers = self.inner.peers.lock().unwrap();
+            peers
+                .inventory
+                .iter_mut()
+                .map(|(peer, inv)| (peer.clone(), inv.take_due(now)))
+                .filter(|(_, items)| !items.is_empty())
+                .collect()
+        };
+        for (peer, mut items) in due {
+            {
+                let mempool = self.inner.mempool.lock().unwrap();
+                items.retain(|txid| mempool.contains(txid));
+            }
+            if items.is_empty() {
+                continue;
+            }
+            if let Err(e) = self.send_inv(&peer, "tx", items) {
+                error!("failed to announce transactions to {}: {}", peer, e);
+            }
+        }
     }
 
     fn node_is_known(&self, addr: &str) -> bool {
-        self.inner.lock().unwrap().known_nodes.get(addr).is_some()
+        self.inner.peers.lock().unwrap().known_nodes.contains(addr)
     }
 
-    fn replace_in_transit(&self, hashs: Vec<String>) {
-        let bit = &mut self.inner.lock().unwrap().blocks_in_transit;
-        bit.clone_from(&hashs);
+    /// request_next_block asks for the next announced block once the previous
+    /// request was answered or timed out
+    fn request_next_block(&self) -> Result<()> {
+        let next = self.inner.block_download.lock().unwrap().next_request(Instant::now());
+        match next {
+            Some((block_hash, peer)) => self.send_get_data(&peer, "block", &block_hash),
+            None => Ok(()),
+        }
     }
 
-    fn get_in_transit(&self) -> Vec<String> {
-        self.inner.lock().unwrap().blocks_in_transit.clone()
+    /// download_full_block queues block_hash for download from the peer which
+    /// sent it as a compact block which could not be rebuilt
+    fn download_full_block(&self, from: &str, block_hash: &str) -> Result<()> {
+        self.inner
+            .block_download
+            .lock()
+            .unwrap()
+            .announce(from, &[block_hash.to_string()]);
+        self.request_next_block()
     }
 
-    fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.inner.lock().unwrap().mempool.get(addr) {
-            Some(tx) => Some(tx.clone()),
-            None => None,
+    /// expire_partial_blocks gives up rebuilding the compact blocks whose
+    /// missing transactions did not arrive in time, downloading them in full
+    fn expire_partial_blocks(&self) -> Result<()> {
+        let now = Instant::now();
+        let expired: Vec<(String, String)> = {
+            let mut partial_blocks = self.inner.partial_blocks.lock().unwrap();
+            let stale: Vec<String> = partial_blocks
+                .iter()
+                .filter(|(_, (_, _, at))| now.duration_since(*at) >= PARTIAL_BLOCK_TIMEOUT)
+                .map(|(hash, _)| hash.clone())
+                .collect();
+            stale
+                .into_iter()
+                .filter_map(|hash| partial_blocks.remove(&hash).map(|(_, from, _)| (hash, from)))
+                .collect()
+        };
+        for (block_hash, from) in expired {
+            warn!("missing transactions of block {} did not arrive, request the full block", block_hash);
+            self.download_full_block(&from, &block_hash)?;
         }
+        Ok(())
+    }
+
+    pub(crate) fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
+        self.inner.mempool.lock().unwrap().get(addr).map(|e| e.tx.clone())
+    }
+
+    pub(crate) fn get_mempool(&self) -> HashMap<String, Transaction> {
+        self.inner.mempool.lock().unwrap().transactions()
     }
 
-    fn get_mempool(&self) -> HashMap<String, Transaction> {
-        self.inner.lock().unwrap().mempool.clone()
+    pub(crate) fn get_mempool_entry(&self, txid: &str) -> Option<MempoolEntry> {
+        self.inner.mempool.lock().unwrap().get(txid).cloned()
     }
 
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+    pub(crate) fn get_mempool_info(&self) -> MempoolInfo {
+        self.inner.mempool.lock().unwrap().info()
     }
 
-    fn clear_mempool(&self) {
-        self.inner.lock().unwrap().mempool.clear()
+    /// wallet_passphrase checks passphrase and keeps the wallet key in memory
+    /// for timeout, it is never written to disk and wiped once the timeout fires
+    pub(crate) fn wallet_passphrase(&self, passphrase: &str, timeout: Duration) -> Result<()> {
+        let mut wallets = Wallets::new()?;
+        wallets.unlock(passphrase)?;
+        let key = wallets.master_key().map(|k| k.to_vec()).unwrap_or_default();
+        let until = Instant::now() + timeout;
+        *self.inner.wallet_unlock.lock().unwrap() = Some(WalletUnlock { key, until });
+        info!("wallet unlocked for {}s", timeout.as_secs());
+
+        let server = self.clone_handle();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            let mut unlock = server.inner.wallet_unlock.lock().unwrap();
+            // a later walletpassphrase may have extended the session
+            if matches!(&*unlock, Some(u) if u.until <= Instant::now()) {
+                *unlock = None;
+                info!("wallet locked, unlock timeout reached");
+            }
+        });
+        Ok(())
     }
 
-    fn get_best_height(&self) -> Result<i32> {
-        self.inner.lock().unwrap().utxo.blockchain.get_best_height()
+    /// wallet_lock wipes the key of the unlocked wallet
+    pub(crate) fn wallet_lock(&self) {
+        *self.inner.wallet_unlock.lock().unwrap() = None;
+    }
+
+    /// wallet_key returns the key of the wallet while it is unlocked
+    fn wallet_key(&self) -> Option<Vec<u8>> {
+        let mut unlock = self.inner.wallet_unlock.lock().unwrap();
+        if matches!(&*unlock, Some(u) if u.until <= Instant::now()) {
+            *unlock = None;
+        }
+        unlock.as_ref().map(|u| u.key.clone())
+    }
+
+    /// open_wallet loads the wallets, with the secret keys of an encrypted wallet
+    /// while walletpassphrase keeps it unlocked
+    pub(crate) fn open_wallet(&self) -> Result<Wallets> {
+        let mut wallets = Wallets::new()?;
+        if wallets.is_locked() {
+            if let Some(key) = self.wallet_key() {
+                wallets.unlock_with_key(key)?;
+            }
+        }
+        Ok(wallets)
+    }
+
+    /// wallet_fee_rate is the fee per 1000 bytes the wallet sends pay: the minimum
+    /// relay fee, or the mempool minimum when it is higher
+    fn wallet_fee_rate(&self) -> i32 {
+        let info = self.get_mempool_info();
+        info.min_fee_rate.max(info.min_relay_fee as f64).ceil() as i32
+    }
+
+    /// send_from pays amount to `to` out of the coins of the wallet address from,
+    /// the change going back to it, and relays the transaction. Returns its id
+    pub(crate) fn send_from(&self, wallets: &Wallets, from: &str, to: &str, amount: i32) -> Result<String> {
+        let wallet = match wallets.get_wallet(from) {
+            Some(w) => w,
+            None => return Err(format_err!("{} is not a wallet address", from)),
+        };
+        let fee_rate = self.wallet_fee_rate();
+        let tx = Transaction::new_utxo(wallet, to, amount, fee_rate, &self.inner.utxo.lock().unwrap())?;
+        if tx.vout.len() > 1 {
+            wallets.record_change(&tx.id, 1)?;
+        }
+        let txid = tx.id.clone();
+        self.submit_transaction(tx)?;
+        Ok(txid)
+    }
+
+    /// send_many pays the (address, amount) outputs in one transaction from the
+    /// coins of every wallet address picked by the strategy coin selection, and
+    /// relays it. Returns its id
+    pub(crate) fn send_many(&self, wallets: &mut Wallets, outputs: &[(String, i32)], strategy: &str) -> Result<String> {
+        let selector = coinselect::selector(strategy)?;
+        let fee_rate = self.wallet_fee_rate();
+        let tx = Transaction::new_wallet_send(
+            wallets,
+            outputs,
+            selector.as_ref(),
+            fee_rate,
+            &self.inner.utxo.lock().unwrap(),
+        )?;
+        let txid = tx.id.clone();
+        self.submit_transaction(tx)?;
+        Ok(txid)
+    }
+
+    /// bump_fee replaces a wallet transaction of the mempool by one taking a
+    /// higher fee out of its change output. The new fee pays fee_rate when given,
+    /// else the old fee plus the incremental relay fee. Returns the new txid, the
+    /// old and the new fee
+    pub(crate) fn bump_fee(&self, wallets: &Wallets, txid: &str, fee_rate: Option<i32>) -> Result<(String, i32, i32)> {
+        let entry = match self.get_mempool_entry(txid) {
+            Some(entry) => entry,
+            None => return Err(format_err!("transaction {} is not in the mempool", txid)),
+        };
+        if !entry.tx.signals_rbf() {
+            return Err(format_err!("transaction {} does not signal replace-by-fee", txid));
+        }
+        let old_fee = entry.fee;
+        let mut tx = entry.tx;
+
+        let prevouts = self.get_prevouts(&tx)?;
+        let all_owned = tx.vin.iter().all(|vin| {
+            prevouts
+                .get(&(vin.txid.clone(), vin.vout))
+                .map(|out| wallets.get_wallet(&address_from_pub_key_hash(&out.pub_key_hash)).is_some())
+                .unwrap_or(false)
+        });
+        if !all_owned {
+            return Err(format_err!("transaction {} spends outputs the wallet cannot sign", txid));
+        }
+
+        let size = tx.size()?;
+        let mut new_fee = old_fee + mempool::fee_for_size(INCREMENTAL_RELAY_FEE, size);
+        if let Some(rate) = fee_rate {
+            new_fee = new_fee.max(mempool::fee_for_size(rate, size));
+        }
+        // the change output was recorded when the wallet built the transaction, a
+        // payment to one of its own addresses must not be mistaken for it
+        let change = match wallets.change_output(txid)? {
+            Some(n) => n as usize,
+            None => return Err(format_err!("transaction {} has no change output known to the wallet", txid)),
+        };
+        match tx.vout.get(change) {
+            Some(out) if out.value > new_fee - old_fee => {}
+            _ => return Err(format_err!("transaction {} has no change output large enough to pay {}", txid, new_fee)),
+        }
+        tx.vout[change].value -= new_fee - old_fee;
+        for vin in &mut tx.vin {
+            vin.signature.clear();
+            vin.pub_key.clear();
+        }
+        tx.sign_with_wallets(wallets, &prevouts)?;
+
+        let new_txid = tx.id.clone();
+        self.submit_transaction(tx)?;
+        wallets.record_change(&new_txid, change as i32)?;
+        Ok((new_txid, old_fee, new_fee))
+    }
+
+    /// insert_mempool adds tx to the mempool, replacing the transactions it
+    /// conflicts with when the replace-by-fee rules allow it and evicting the
+    /// lowest paying ones when the mempool is full
+    fn insert_mempool(&self, tx: Transaction) -> Result<()> {
+        let entry = self.mempool_entry(tx)?;
+        let txid = entry.tx.id.clone();
+        let evicted = self.inner.mempool.lock().unwrap().add(entry)?;
+        if !evicted.is_empty() {
+            info!("transaction {} evicted {:?}", txid, evicted);
+        }
+        Ok(())
+    }
+
+    fn remove_from_mempool(&self, txs: &[Transaction]) {
+        self.inner.mempool.lock().unwrap().remove_for_block(txs);
+    }
+
+    /// mempool_cutoff is the time before which mempool transactions expire
+    fn mempool_cutoff(&self) -> u64 {
+        addrman::now().saturating_sub(self.mempool_expiry.as_secs())
+    }
+
+    fn expire_mempool(&self) {
+        let expired = self.inner.mempool.lock().unwrap().expire(self.mempool_cutoff());
+        if !expired.is_empty() {
+            info!("expired mempool transactions {:?}", expired);
+        }
+    }
+
+    /// save_mempool writes the mempool entries to disk, parents before their children
+    fn save_mempool(&self) -> Result<()> {
+        let entries = self.inner.mempool.lock().unwrap().entries();
+        let db = sled::open(MEMPOOL_FILE)?;
+        db.clear()?;
+        for (i, entry) in entries.iter().enumerate() {
+            db.insert((i as u64).to_be_bytes(), serialize(entry)?)?;
+        }
+        db.flush()?;
+        info!("saved {} mempool transactions", entries.len());
+        Ok(())
+    }
+
+    /// load_mempool adds back the saved mempool transactions that did not expire
+    /// and are still valid
+    fn load_mempool(&self) -> Result<()> {
+        let db = sled::open(MEMPOOL_FILE)?;
+        let cutoff = self.mempool_cutoff();
+        let (mut loaded, mut dropped) = (0, 0);
+        for item in db.iter() {
+            let (_, v) = item?;
+            let saved: MempoolEntry = deserialize(&v)?;
+            let txid = saved.tx.id.clone();
+            if saved.time < cutoff {
+                debug!("saved transaction {} expired", txid);
+                dropped += 1;
+                continue;
+            }
+            match self.accept_saved(saved) {
+                Ok(()) => loaded += 1,
+                Err(e) => {
+                    debug!("dropped saved transaction {}: {}", txid, e);
+                    dropped += 1;
+                }
+            }
+        }
+        info!("loaded {} mempool transactions, dropped {}", loaded, dropped);
+        Ok(())
+    }
+
+    /// accept_saved revalidates a saved mempool entry against the current chain,
+    /// keeping the time it first entered the mempool
+    fn accept_saved(&self, saved: MempoolEntry) -> Result<()> {
+        if self.find_confirmed_tx(&saved.tx.id).is_some() {
+            return Err(format_err!("transaction already in block chain"));
+        }
+        if !self.verify_tx(&saved.tx)? {
+            return Err(format_err!("ERROR: Invalid transaction"));
+        }
+        if !self.is_mature(&saved.tx)? {
+            return Err(format_err!("transaction spends an immature coinbase"));
+        }
+        let mut entry = self.mempool_entry(saved.tx)?;
+        entry.time = saved.time;
+        self.inner.mempool.lock().unwrap().add(entry)?;
+        Ok(())
+    }
+
+    /// mempool_entry looks up the outputs tx spends to compute its fee
+    fn mempool_entry(&self, tx: Transaction) -> Result<MempoolEntry> {
+        let prevouts = self.get_prevouts(&tx)?;
+        MempoolEntry::new(tx, &prevouts)
+    }
+
+    /// get_prevouts returns the outputs tx spends, keyed by (txid, vout)
+    fn get_prevouts(&self, tx: &Transaction) -> Result<HashMap<(String, i32), TXOutput>> {
+        let prev_txs = self.get_prev_txs(tx)?;
+        let mut prevouts = HashMap::new();
+        for vin in &tx.vin {
+            if let Some(out) = prev_txs[&vin.txid].vout.get(vin.vout as usize) {
+                prevouts.insert((vin.txid.clone(), vin.vout), out.clone());
+            }
+        }
+        Ok(prevouts)
+    }
+
+    /// get_prev_txs returns the transactions tx spends outputs of, taken from the
+    /// mempool when they are not confirmed yet
+    fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+        let mut prev_txs = HashMap::new();
+        {
+            let mempool = self.inner.mempool.lock().unwrap();
+            for vin in &tx.vin {
+                if let Some(entry) = mempool.get(&vin.txid) {
+                    prev_txs.insert(vin.txid.clone(), entry.tx.clone());
+                }
+            }
+        }
+        let utxo = self.inner.utxo.lock().unwrap();
+        for vin in &tx.vin {
+            if !prev_txs.contains_key(&vin.txid) {
+                let prev_tx = utxo.blockchain.find_transacton(&vin.txid)?;
+                prev_txs.insert(vin.txid.clone(), prev_tx);
+            }
+        }
+        Ok(prev_txs)
+    }
+
+
+    pub(crate) fn get_best_height(&self) -> Result<i32> {
+        self.inner.utxo.lock().unwrap().blockchain.get_best_height()
     }
 
     fn get_block_hashs(&self) -> Vec<String> {
-        self.inner.lock().unwrap().utxo.blockchain.get_block_hashs()
+        self.inner.utxo.lock().unwrap().blockchain.get_block_hashs()
     }
 
-    fn get_block(&self, block_hash: &str) -> Result<Block> {
+    pub(crate) fn get_block(&self, block_hash: &str) -> Result<Block> {
         self.inner
+            .utxo
             .lock()
             .unwrap()
-            .utxo
             .blockchain
             .get_block(block_hash)
     }
 
+    /// verify_tx checks the signatures of tx, whose inputs may spend outputs of
+    /// mempool transactions
     fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
-        self.inner
-            .lock()
-            .unwrap()
-            .utxo
-            .blockchain
-            .verify_transacton(tx)
+        if tx.is_coinbase() {
+            return Ok(true);
+        }
+        tx.verify(self.get_prev_txs(tx)?)
+    }
+
+    /// is_mature checks that tx spends no coinbase output too young for the next block
+    fn is_mature(&self, tx: &Transaction) -> Result<bool> {
+        let utxo = self.inner.utxo.lock().unwrap();
+        let height = utxo.blockchain.get_best_height()? + 1;
+        utxo.blockchain.verify_coinbase_maturity(tx, height, &utxo.blockchain.tip)
+    }
+
+    fn has_block(&self, block_hash: &str) -> Result<bool> {
+        self.inner.utxo.lock().unwrap().blockchain.has_block(block_hash)
     }
 
     fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+        let hash = block.get_hash();
+        let txs = block.get_transaction().clone();
+        let new_tip = {
+            let mut utxo = self.inner.utxo.lock().unwrap();
+            let old_tip = utxo.blockchain.tip.clone();
+            utxo.blockchain.add_block(block)?;
+            utxo.blockchain.tip != old_tip
+        };
+        self.inner.block_download.lock().unwrap().received(&hash);
+        self.remove_from_mempool(&txs);
+        if new_tip {
+            self.inner.miner.new_tip();
+        }
+        Ok(())
+    }
+
+    /// find_confirmed_tx looks a transaction up in the chain, with the block holding it
+    pub(crate) fn find_confirmed_tx(&self, txid: &str) -> Option<(Transaction, Block)> {
+        let utxo = self.inner.utxo.lock().unwrap();
+        for block in utxo.blockchain.iter() {
+            if let Some(tx) = block.get_transaction().iter().find(|tx| tx.id == txid) {
+                return Some((tx.clone(), block.clone()));
+            }
+        }
+        None
     }
 
-    fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+    /// get_balance sums the unspent outputs locked with pub_key_hash
+    pub(crate) fn get_balance(&self, pub_key_hash: &[u8]) -> Result<i32> {
+        let utxos = self.inner.utxo.lock().unwrap().find_utxo(pub_key_hash)?;
+        Ok(utxos.iter().map(|out| out.value).sum())
+    }
+
+    pub(crate) fn get_best_block_hash(&self) -> String {
+        self.inner.utxo.lock().unwrap().blockchain.tip.clone()
+    }
+
+    /// get_tip returns the hash and height of the best block
+    fn get_tip(&self) -> Result<(String, i32)> {
+        let utxo = self.inner.utxo.lock().unwrap();
+        Ok((utxo.blockchain.tip.clone(), utxo.blockchain.get_best_height()?))
+    }
+
+    /// add_mined_block stores a block mined on top of prev_hash, unless the tip moved meanwhile
+    fn add_mined_block(&self, prev_hash: &str, block: Block) -> Result<bool> {
+        let mut utxo = self.inner.utxo.lock().unwrap();
+        if utxo.blockchain.tip != prev_hash {
+            return Ok(false);
+        }
+        utxo.blockchain.add_block(block)?;
+        utxo.reindex()?;
+        Ok(true)
     }
 
     fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+        self.inner.utxo.lock().unwrap().reindex()
     }
 
     /* -----------------------------------------------------*/
 
     fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == &self.node_address {
+        if self.is_self(addr) {
             return Ok(());
         }
-        let mut stream = match TcpStream::connect(addr) {
+        let mut stream = match connect(addr) {
             Ok(s) => s,
             Err(_) => {
-                self.remove_node(addr);
+                self.connect_failed(addr);
                 return Ok(());
             }
         };
+        self.mark_good(addr);
 
-        stream.write(data)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        stream.write_all(data)?;
 
         info!("data send successfully");
         Ok(())
@@ -264,10 +1008,48 @@ impl Server {
         self.send_data(addr, &data)
     }
 
+    fn send_compact_block(&self, addr: &str, b: &CompactBlock) -> Result<()> {
+        info!("send compact block to: {} block hash: {}", addr, b.get_hash());
+        let data = CmpctBlockmsg {
+            addr_from: self.node_address.clone(),
+            block: b.clone(),
+        };
+        let data = serialize(&(cmd_to_bytes("cmpctblock"), data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn send_get_block_txn(&self, addr: &str, block_hash: &str, indexes: Vec<usize>) -> Result<()> {
+        info!("send get block txn to: {} block hash: {} count: {}", addr, block_hash, indexes.len());
+        let data = GetBlockTxnmsg {
+            addr_from: self.node_address.clone(),
+            block_hash: block_hash.to_string(),
+            indexes,
+        };
+        let data = serialize(&(cmd_to_bytes("getblocktxn"), data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn send_block_txn(&self, addr: &str, block_hash: &str, transactions: Vec<Transaction>) -> Result<()> {
+        info!("send block txn to: {} block hash: {}", addr, block_hash);
+        let data = BlockTxnmsg {
+            addr_from: self.node_address.clone(),
+            block_hash: block_hash.to_string(),
+            transactions,
+        };
+        let data = serialize(&(cmd_to_bytes("blocktxn"), data))?;
+        self.send_data(addr, &data)
+    }
+
     fn send_addr(&self, addr: &str) -> Result<()> {
         info!("send address info to: {}", addr);
-        let nodes = self.get_known_nodes();
-        let data = serialize(&(cmd_to_bytes("addr"), nodes))?;
+        let mut addrs = self.inner.peers.lock().unwrap().addrman.sample(ADDR_GOSSIP_SIZE);
+        addrs.retain(|(a, _)| a != addr);
+        addrs.push((self.node_address.clone(), addrman::now()));
+        let data = Addrmsg {
+            addr_from: self.node_address.clone(),
+            addrs,
+        };
+        let data = serialize(&(cmd_to_bytes("addr"), data))?;
         self.send_data(addr, &data)
     }
 
@@ -346,12 +1128,30 @@ impl Server {
         Ok(())
     }
 
-    fn handle_addr(&self, msg: Vec<String>) -> Result<()> {
-        info!("receive address msg: {:#?}", msg);
-        for node in msg {
-            self.add_nodes(&node);
+    /// handle_addr adds the gossiped addresses to the address book, rate limited and
+    /// bucketed by peer, the socket address the message came in on
+    fn handle_addr(&self, msg: Addrmsg, peer: &SocketAddr) -> Result<()> {
+        let peer = peer.to_string();
+        info!("receive address msg from: {} ({}) count: {}", msg.addr_from, peer, msg.addrs.len());
+        if msg.addrs.len() > MAX_ADDR_PER_MSG {
+            return Err(format_err!("too many addresses in addr msg from {}", msg.addr_from));
+        }
+
+        let allowed = self.take_addr_tokens(&peer, msg.addrs.len());
+        if allowed < msg.addrs.len() {
+            debug!(
+                "rate limit addr msg from: {}, dropped {} addresses",
+                peer,
+                msg.addrs.len() - allowed
+            );
+        }
+
+        let mut peers = self.inner.peers.lock().unwrap();
+        for (addr, last_seen) in msg.addrs.into_iter().take(allowed) {
+            if !self.is_self(&addr) {
+                peers.addrman.add(&addr, &peer, last_seen);
+            }
         }
-        //self.request_blocks()?;
         Ok(())
     }
 
@@ -361,43 +1161,130 @@ impl Server {
             msg.addr_from,
             msg.block.get_hash()
         );
-        self.add_block(msg.block)?;
-
-        let mut in_transit = self.get_in_transit();
-        if in_transit.len() > 0 {
-            let block_hash = &in_transit[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
-            in_transit.remove(0);
-            self.replace_in_transit(in_transit);
-        } else {
+        self.accept_block(msg.block)?;
+
+        self.request_next_block()?;
+        if self.inner.block_download.lock().unwrap().is_empty() {
             self.utxo_reindex()?;
         }
-
         Ok(())
     }
 
-    fn handle_inv(&self, msg: Invmsg) -> Result<()> {
+    /// handle_cmpct_block rebuilds a new block from the mempool, asking the
+    /// sender for the transactions not found
+    fn handle_cmpct_block(&self, msg: CmpctBlockmsg) -> Result<()> {
+        let block_hash = msg.block.get_hash();
+        info!("receive compact block msg: {}, {}", msg.addr_from, block_hash);
+        if self.has_block(&block_hash)? {
+            return Ok(());
+        }
+        let partial = PartialBlock::new(msg.block, &self.get_mempool());
+        let missing = partial.missing();
+        if missing.is_empty() {
+            return self.accept_partial_block(&msg.addr_from, partial);
+        }
+
+        self.expire_partial_blocks()?;
+        let evicted = {
+            let mut partial_blocks = self.inner.partial_blocks.lock().unwrap();
+            let mut evicted = None;
+            if partial_blocks.len() >= MAX_PARTIAL_BLOCKS {
+                let oldest = partial_blocks
+                    .iter()
+                    .min_by_key(|(_, (_, _, at))| *at)
+                    .map(|(hash, _)| hash.clone());
+                evicted = oldest.and_then(|hash| partial_blocks.remove(&hash).map(|(_, from, _)| (hash, from)));
+            }
+            partial_blocks.insert(block_hash.clone(), (partial, msg.addr_from.clone(), Instant::now()));
+            evicted
+        };
+        if let Some((hash, from)) = evicted {
+            self.download_full_block(&from, &hash)?;
+        }
+        self.send_get_block_txn(&msg.addr_from, &block_hash, missing)
+    }
+
+    fn handle_get_block_txn(&self, msg: GetBlockTxnmsg) -> Result<()> {
+        info!("receive get block txn msg: {}, {}", msg.addr_from, msg.block_hash);
+        let block = self.get_block(&msg.block_hash)?;
+        let mut transactions = Vec::new();
+        for i in msg.indexes {
+            match block.get_transaction().get(i) {
+                Some(tx) => transactions.push(tx.clone()),
+                None => return Err(format_err!("block {} has no transaction {}", msg.block_hash, i)),
+            }
+        }
+        self.send_block_txn(&msg.addr_from, &msg.block_hash, transactions)
+    }
+
+    fn handle_block_txn(&self, msg: BlockTxnmsg) -> Result<()> {
+        info!("receive block txn msg: {}, {}", msg.addr_from, msg.block_hash);
+        let partial = self.inner.partial_blocks.lock().unwrap().remove(&msg.block_hash);
+        let mut partial = match partial {
+            Some((partial, _, _)) => partial,
+            None => return Err(format_err!("block {} was not requested", msg.block_hash)),
+        };
+        if let Err(e) = partial.fill(msg.transactions) {
+            warn!("{}, request the full block", e);
+            return self.download_full_block(&msg.addr_from, &msg.block_hash);
+        }
+        self.accept_partial_block(&msg.addr_from, partial)
+    }
+
+    /// accept_partial_block adds a rebuilt compact block, falling back to the
+    /// full block from the sender when the rebuilt one does not match the header
+    fn accept_partial_block(&self, from: &str, partial: PartialBlock) -> Result<()> {
+        let block = match partial.to_block() {
+            Ok(block) => block,
+            Err(e) => {
+                warn!("{}, request the full block", e);
+                return self.download_full_block(from, &partial.get_hash());
+            }
+        };
+        if !self.has_block(&block.get_prev_hash())? {
+            info!("parent of block {} is unknown, catch up with {}", block.get_hash(), from);
+            return self.send_get_blocks(from);
+        }
+        self.accept_block(block)?;
+        self.utxo_reindex()
+    }
+
+    /// accept_block adds a block received from a peer once its proof of work
+    /// checks out, add_block then refuses it unless it extends a stored block
+    fn accept_block(&self, block: Block) -> Result<()> {
+        if !block.validate()? {
+            return Err(format_err!("block {} has an invalid proof of work", block.get_hash()));
+        }
+        self.add_block(block)
+    }
+
+    fn handle_inv(&self, msg: Invmsg, peer: &SocketAddr) -> Result<()> {
         info!("receive inv msg: {:#?}", msg);
+        if msg.items.len() > MAX_INV_PER_MSG {
+            return Err(format_err!("too many items in inv msg from {}", msg.addr_from));
+        }
         if msg.kind == "block" {
-            let block_hash = &msg.items[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
-
-            let mut new_in_transit = Vec::new();
-            for b in &msg.items {
-                if b != block_hash {
-                    new_in_transit.push(b.clone());
+            let mut unknown = Vec::new();
+            for block_hash in &msg.items {
+                if !self.has_block(block_hash)? {
+                    unknown.push(block_hash.clone());
                 }
             }
-            self.replace_in_transit(new_in_transit);
+            self.inner
+                .block_download
+                .lock()
+                .unwrap()
+                .announce(&msg.addr_from, &unknown);
+            self.request_next_block()?;
         } else if msg.kind == "tx" {
-            let txid = &msg.items[0];
-            match self.get_mempool_tx(txid) {
-                Some(tx) => {
-                    if tx.id.is_empty() {
-                        self.send_get_data(&msg.addr_from, "tx", txid)?
-                    }
-                }
-                None => self.send_get_data(&msg.addr_from, "tx", txid)?,
+            self.add_known_inventory(&msg.addr_from, peer, &msg.items);
+            let missing: Vec<&String> = {
+                let mempool = self.inner.mempool.lock().unwrap();
+                msg.items.iter().filter(|txid| !mempool.contains(txid)).collect()
+            };
+            // one announcer is asked at a time, the others are left alone until it times out
+            for txid in self.request_txs(&missing) {
+                self.send_get_data(&msg.addr_from, "tx", txid)?;
             }
         }
         Ok(())
@@ -405,93 +1292,88 @@ impl Server {
 
     fn handle_get_blocks(&self, msg: GetBlocksmsg) -> Result<()> {
         info!("receive get blocks msg: {:#?}", msg);
-        let block_hashs = self.get_block_hashs();
+        let mut block_hashs = self.get_block_hashs();
+        // parents first, the order the blocks are downloaded and connected in
+        block_hashs.reverse();
         self.send_inv(&msg.addr_from, "block", block_hashs)?;
         Ok(())
     }
 
-    fn handle_get_data(&self, msg: GetDatamsg) -> Result<()> {
+    fn handle_get_data(&self, msg: GetDatamsg, peer: &SocketAddr) -> Result<()> {
         info!("receive get data msg: {:#?}", msg);
         if msg.kind == "block" {
             let block = self.get_block(&msg.id)?;
             self.send_block(&msg.addr_from, &block)?;
         } else if msg.kind == "tx" {
-            let tx = self.get_mempool_tx(&msg.id).unwrap();
+            // the transaction may have been mined or replaced since it was announced
+            let tx = match self.get_mempool_tx(&msg.id) {
+                Some(tx) => tx,
+                None => return Err(format_err!("transaction {} is not in the mempool", msg.id)),
+            };
+            self.add_known_inventory(&msg.addr_from, peer, std::slice::from_ref(&tx.id));
             self.send_tx(&msg.addr_from, &tx)?;
         }
         Ok(())
     }
 
-    fn handle_tx(&self, msg: Txmsg) -> Result<()> {
+    fn handle_tx(&self, msg: Txmsg, peer: &SocketAddr) -> Result<()> {
         info!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
-        self.insert_mempool(msg.transaction.clone());
+        self.inner.requested_txs.lock().unwrap().remove(&msg.transaction.id);
+        self.add_known_inventory(&msg.addr_from, peer, std::slice::from_ref(&msg.transaction.id));
+        self.accept_transaction(msg.transaction, &msg.addr_from)
+    }
 
-        let known_nodes = self.get_known_nodes();
-        if self.node_address == KNOWN_NODE1 {
-            for node in known_nodes {
-                if node != self.node_address && node != msg.addr_from {
-                    self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
-                }
-            }
-        } else {
-            let mut mempool = self.get_mempool();
-            debug!("Current mempool: {:#?}", &mempool);
-            if mempool.len() >= 1 && !self.mining_address.is_empty() {
-                loop {
-                    let mut txs = Vec::new();
-
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
-
-                    if txs.is_empty() {
-                        return Ok(());
-                    }
-
-                    let cbtx =
-                        Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
-                    txs.push(cbtx);
-
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
-                    }
-
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
-
-                    for node in self.get_known_nodes() {
-                        if node != self.node_address {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
-                    }
-
-                    if mempool.len() == 0 {
-                        break;
-                    }
-                }
-                self.clear_mempool();
-            }
+    /// submit_transaction validates a transaction from a local client before relaying it
+    pub(crate) fn submit_transaction(&self, tx: Transaction) -> Result<()> {
+        self.accept_transaction(tx, "")
+    }
+
+    /// accept_transaction validates tx before relaying it, from is the peer it came from
+    fn accept_transaction(&self, tx: Transaction, from: &str) -> Result<()> {
+        if tx.is_coinbase() || !self.verify_tx(&tx)? {
+            return Err(format_err!("ERROR: Invalid transaction"));
+        }
+        if !self.is_mature(&tx)? {
+            return Err(format_err!("transaction spends an immature coinbase"));
+        }
+        if self.get_mempool_tx(&tx.id).is_some() {
+            return Err(format_err!("transaction already in mempool"));
         }
+        if self.find_confirmed_tx(&tx.id).is_some() {
+            return Err(format_err!("transaction already in block chain"));
+        }
+        self.relay_transaction(tx, from)
+    }
 
+    /// relay_transaction adds tx to the mempool and queues its announcement to the
+    /// peers which do not know it
+    fn relay_transaction(&self, tx: Transaction, from: &str) -> Result<()> {
+        let txid = tx.id.clone();
+        self.insert_mempool(tx)?;
+        self.queue_announcement(&txid, from);
+        self.inner.miner.mempool_changed();
         Ok(())
     }
 
-    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+    fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let peer = stream.peer_addr()?;
         let mut buffer = Vec::new();
-        let count = stream.read_to_end(&mut buffer)?;
+        let count = stream.take(MAX_MESSAGE_SIZE).read_to_end(&mut buffer)?;
         info!("Accept request: length {}", count);
 
         let cmd = bytes_to_cmd(&buffer)?;
 
         match cmd {
-            Message::Addr(data) => self.handle_addr(data)?,
+            Message::Addr(data) => self.handle_addr(data, &peer)?,
             Message::Block(data) => self.handle_block(data)?,
-            Message::Inv(data) => self.handle_inv(data)?,
+            Message::CmpctBlock(data) => self.handle_cmpct_block(data)?,
+            Message::GetBlockTxn(data) => self.handle_get_block_txn(data)?,
+            Message::BlockTxn(data) => self.handle_block_txn(data)?,
+            Message::Inv(data) => self.handle_inv(data, &peer)?,
             Message::GetBlock(data) => self.handle_get_blocks(data)?,
-            Message::GetData(data) => self.handle_get_data(data)?,
-            Message::Tx(data) => self.handle_tx(data)?,
+            Message::GetData(data) => self.handle_get_data(data, &peer)?,
+            Message::Tx(data) => self.handle_tx(data, &peer)?,
             Message::Version(data) => self.handle_version(data)?,
         }
 
@@ -499,6 +1381,94 @@ impl Server {
     }
 }
 
+impl MiningContext for Server {
+    fn block_template(&self) -> Result<Option<Block>> {
+        let packages = self.inner.mempool.lock().unwrap().select_packages(MAX_BLOCK_TX_SIZE);
+        debug!("Current mempool packages: {:#?}", &packages);
+        let mut txs: Vec<Transaction> = Vec::new();
+        let mut dropped = HashSet::new();
+        for tx in packages {
+            // the descendants of an invalid transaction are invalid as well
+            if tx.vin.iter().any(|vin| dropped.contains(&vin.txid))
+                || !self.verify_tx(&tx).unwrap_or(false)
+                || !self.is_mature(&tx).unwrap_or(false)
+            {
+                dropped.insert(tx.id.clone());
+                continue;
+            }
+            txs.push(tx);
+        }
+
+        if txs.is_empty() && !self.mine_empty_blocks {
+            return Ok(None);
+        }
+
+        let fees: i32 = {
+            let mempool = self.inner.mempool.lock().unwrap();
+            txs.iter().filter_map(|tx| mempool.get(&tx.id)).map(|entry| entry.fee).sum()
+        };
+        let cbtx = Transaction::new_coinbase_with_fees(self.mining_address.clone(), String::new(), fees)?;
+        txs.push(cbtx);
+
+        let (tip, height) = self.get_tip()?;
+        Ok(Some(Block::new_template(txs, tip, height + 1)?))
+    }
+
+    fn submit_block(&self, block: Block) -> Result<bool> {
+        if !self.add_mined_block(&block.get_prev_hash(), block.clone())? {
+            return Ok(false);
+        }
+        self.remove_from_mempool(block.get_transaction());
+
+        let compact = CompactBlock::new(&block);
+        for node in self.get_known_nodes() {
+            if !self.is_self(&node) {
+                self.send_compact_block(&node, &compact)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// accept_loop queues incoming connections for the workers
+fn accept_loop(listener: TcpListener, queue: mpsc::SyncSender<TcpStream>) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                error!("failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        match queue.try_send(stream) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("all workers busy, drop connection"),
+            Err(TrySendError::Disconnected(_)) => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+/// connect opens a connection to addr, giving up after CONNECT_TIMEOUT
+fn connect(addr: &str) -> Result<TcpStream> {
+    let mut last_err = format_err!("could not resolve {}", addr);
+    for sock in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&sock, CONNECT_TIMEOUT) {
+            Ok(s) => return Ok(s),
+            Err(e) => last_err = e.into(),
+        }
+    }
+    Err(last_err)
+}
+
+/// resolves_to reports whether addr names the host peer connected from
+fn resolves_to(addr: &str, peer: &SocketAddr) -> bool {
+    match addr.to_socket_addrs() {
+        Ok(mut socks) => socks.any(|s| s.ip() == peer.ip()),
+        Err(_) => false,
+    }
+}
+
 fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
     let mut data = [0; CMD_LEN];
     for (i, d) in cmd.as_bytes().iter().enumerate() {
@@ -508,22 +1478,34 @@ fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
 }
 
 fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
+    if bytes.len() < CMD_LEN {
+        return Err(format_err!("message too short"));
+    }
     let mut cmd = Vec::new();
     let cmd_bytes = &bytes[..CMD_LEN];
     let data = &bytes[CMD_LEN..];
     for b in cmd_bytes {
-        if 0 as u8 != *b {
+        if *b != 0 {
             cmd.push(*b);
         }
     }
     info!("cmd: {}", String::from_utf8(cmd.clone())?);
 
     if cmd == "addr".as_bytes() {
-        let data: Vec<String> = deserialize(data)?;
+        let data: Addrmsg = deserialize(data)?;
         Ok(Message::Addr(data))
     } else if cmd == "block".as_bytes() {
         let data: Blockmsg = deserialize(data)?;
         Ok(Message::Block(data))
+    } else if cmd == "cmpctblock".as_bytes() {
+        let data: CmpctBlockmsg = deserialize(data)?;
+        Ok(Message::CmpctBlock(data))
+    } else if cmd == "getblocktxn".as_bytes() {
+        let data: GetBlockTxnmsg = deserialize(data)?;
+        Ok(Message::GetBlockTxn(data))
+    } else if cmd == "blocktxn".as_bytes() {
+        let data: BlockTxnmsg = deserialize(data)?;
+        Ok(Message::BlockTxn(data))
     } else if cmd == "inv".as_bytes() {
         let data: Invmsg = deserialize(data)?;
         Ok(Message::Inv(data))
@@ -552,10 +1534,10 @@ mod test {
     #[test]
     fn test_cmd() {
         let mut ws = wallet::Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
+        let wa1 = ws.create_wallet().unwrap();
         let bc = Blockchain::create_blockchain(wa1).unwrap();
         let utxo_set = UTXOSet { blockchain: bc };
-        let server = Server::new("7878", "localhost:3001", utxo_set).unwrap();
+        let server = Server::new("7878", "localhost:3001", utxo_set, &Config::default()).unwrap();
 
         let vmsg = Versionmsg {
             addr_from: server.node_address.clone(),
@@ -1,6 +1,8 @@
 //! transaction implement
 
 use super::*;
+use crate::coinselect::CoinSelector;
+use crate::mempool::fee_for_size;
 use crate::utxoset::*;
 use crate::wallet::*;
 use bincode::serialize;
@@ -11,11 +13,20 @@ use crypto::sha2::Sha256;
 use failure::format_err;
 use rand:: RngCore;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use log::{debug, error, info};
 use rand::rngs::OsRng;
 
 const SUBSIDY: i32 = 10;
+/// COINBASE_MATURITY is how many confirmations a coinbase output needs before it can be spent
+pub const COINBASE_MATURITY: i32 = 100;
+/// DEVNET_COINBASE_MATURITY lets a test network spend a reward in the block after it
+pub const DEVNET_COINBASE_MATURITY: i32 = 1;
+/// SEQUENCE_FINAL is the sequence of inputs that do not allow replacement
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+/// SEQUENCE_RBF is the sequence wallet inputs use to opt in to replace-by-fee,
+/// any sequence below SEQUENCE_FINAL - 1 signals it
+pub const SEQUENCE_RBF: u32 = 0xffff_fffd;
 
 /// TXInput represents a transaction input
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -24,6 +35,7 @@ pub struct TXInput {
     pub vout: i32,
     pub signature: Vec<u8>,
     pub pub_key: Vec<u8>,
+    pub sequence: u32,
 }
 
 /// TXOutput represents a transaction output
@@ -33,10 +45,22 @@ pub struct TXOutput {
     pub pub_key_hash: Vec<u8>,
 }
 
-// TXOutputs collects TXOutput
+// TXOutputs collects the unspent outputs of a transaction
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutputs {
     pub outputs: Vec<TXOutput>,
+    /// indexes are the positions of the outputs in the transaction
+    pub indexes: Vec<i32>,
+    /// height is the height of the block holding the transaction
+    pub height: i32,
+    pub coinbase: bool,
+}
+
+impl TXOutputs {
+    /// IsMature tells whether the outputs can be spent in a block at height
+    pub fn is_mature(&self, height: i32, coinbase_maturity: i32) -> bool {
+        !self.coinbase || height - self.height >= coinbase_maturity
+    }
 }
 
 /// Transaction represents a Bitcoin transaction
@@ -48,62 +72,221 @@ pub struct Transaction {
 }
 
 impl Transaction {
-    /// NewUTXOTransaction creates a new transaction
-    pub fn new_UTXO(wallet: &Wallet, to: &str, amount: i32, utxo: &UTXOSet) -> Result<Transaction> {
+    /// NewUTXOTransaction creates a new transaction paying fee_rate per 1000 bytes,
+    /// the fee is taken off the change, which follows the payment
+    pub fn new_utxo(wallet: &Wallet, to: &str, amount: i32, fee_rate: i32, utxo: &UTXOSet) -> Result<Transaction> {
         info!(
             "new UTXO Transaction from: {} to: {}",
             wallet.get_address(),
             to
         );
-        let mut vin = Vec::new();
-
         let mut pub_key_hash = wallet.public_key.clone();
         hash_pub_key(&mut pub_key_hash);
 
-        let acc_v = utxo.find_spendable_outputs(&pub_key_hash, amount)?;
+        // more inputs raise the fee, which may need more inputs
+        let mut fee = 0;
+        let mut tx = loop {
+            let acc_v = utxo.find_spendable_outputs(&pub_key_hash, amount + fee)?;
+
+            if acc_v.0 < amount + fee {
+                error!("Not Enough balance");
+                return Err(format_err!(
+                    "Not Enough balance: current balance {}, {} needed with the fee",
+                    acc_v.0,
+                    amount + fee
+                ));
+            }
 
-        if acc_v.0 < amount {
-            error!("Not Enough balance");
-            return Err(format_err!(
-                "Not Enough balance: current balance {}",
-                acc_v.0
-            ));
-        }
+            let mut vin = Vec::new();
+            for tx in acc_v.1 {
+                for out in tx.1 {
+                    let input = TXInput {
+                        txid: tx.0.clone(),
+                        vout: out,
+                        signature: Vec::new(),
+                        pub_key: wallet.public_key.clone(),
+                        sequence: SEQUENCE_RBF,
+                    };
+                    vin.push(input);
+                }
+            }
 
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let input = TXInput {
-                    txid: tx.0.clone(),
-                    vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
-                };
-                vin.push(input);
+            let mut vout = vec![TXOutput::new(amount, to.to_string())?];
+            if acc_v.0 > amount + fee {
+                vout.push(TXOutput::new(acc_v.0 - amount - fee, wallet.get_address())?)
             }
-        }
 
-        let mut vout = vec![TXOutput::new(amount, to.to_string())?];
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, wallet.get_address())?)
+            let tx = Transaction {
+                id: String::new(),
+                vin,
+                vout,
+            };
+            let needed = fee_for_size(fee_rate, tx.signed_size()?);
+            if needed <= fee {
+                break tx;
+            }
+            fee = needed;
+        };
+        tx.id = tx.hash()?;
+        utxo.blockchain
+            .sign_transacton(&mut tx, &wallet.secret_key)?;
+        Ok(tx)
+    }
+
+    /// NewWalletSend pays the (address, amount) outputs with coins picked by selector
+    /// among the spendable outputs of every wallet address, plus a fee of fee_rate
+    /// per 1000 bytes. The change goes to a new wallet address, saved before the
+    /// transaction is signed. The inputs opt in to replace-by-fee so that the fee
+    /// can be bumped later
+    pub fn new_wallet_send(
+        wallets: &mut Wallets,
+        outputs: &[(String, i32)],
+        selector: &dyn CoinSelector,
+        fee_rate: i32,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
+        wallets.ensure_unlocked()?;
+        let amount: i32 = outputs.iter().map(|(_, amount)| amount).sum();
+        let pub_key_hashes: HashSet<Vec<u8>> = wallets
+            .get_all_address()
+            .iter()
+            .map(|address| Address::decode(address).unwrap().body)
+            .collect();
+        let coins = utxo.find_spendable_coins(&pub_key_hashes)?;
+
+        // more inputs raise the fee, which may need more inputs
+        let mut fee = 0;
+        let (selected, inputs, total) = loop {
+            let selected = match selector.select(&coins, amount + fee) {
+                Some(selected) => selected,
+                None => {
+                    error!("Not Enough balance");
+                    return Err(format_err!(
+                        "Not Enough balance: current balance {}, {} needed with the fee",
+                        coins.iter().map(|c| c.value()).sum::<i32>(),
+                        amount + fee
+                    ));
+                }
+            };
+            let total: i32 = selected.iter().map(|c| c.value()).sum();
+            let inputs: Vec<(String, i32)> = selected.iter().map(|c| (c.txid.clone(), c.vout)).collect();
+            // every address locks a hash of the same size, any stands in for the change
+            let mut estimate = outputs.to_vec();
+            if let Some((address, _)) = outputs.first().filter(|_| total > amount + fee) {
+                estimate.push((address.clone(), total - amount - fee));
+            }
+            let needed = fee_for_size(fee_rate, Transaction::new_raw(&inputs, &estimate)?.signed_size()?);
+            if needed <= fee {
+                break (selected, inputs, total);
+            }
+            fee = needed;
+        };
+        info!("new wallet Transaction spending {} outputs worth {}, fee {}", selected.len(), total, fee);
+
+        let mut outputs = outputs.to_vec();
+        let mut change_index = None;
+        if total > amount + fee {
+            let change = wallets.create_wallet()?;
+            wallets.save_all()?;
+            change_index = Some(outputs.len() as i32);
+            outputs.push((change, total - amount - fee));
+        }
+        let mut tx = Transaction::new_raw(&inputs, &outputs)?;
+        for vin in &mut tx.vin {
+            vin.sequence = SEQUENCE_RBF;
         }
+        let prevouts = selected
+            .into_iter()
+            .map(|c| ((c.txid, c.vout), c.output))
+            .collect();
+        if !tx.sign_with_wallets(wallets, &prevouts)? {
+            return Err(format_err!("ERROR: could not sign every input"));
+        }
+        if let Some(vout) = change_index {
+            wallets.record_change(&tx.id, vout)?;
+        }
+        Ok(tx)
+    }
 
+    /// NewRaw creates an unsigned transaction spending the (txid, vout) inputs
+    /// into the (address, amount) outputs
+    pub fn new_raw(inputs: &[(String, i32)], outputs: &[(String, i32)]) -> Result<Transaction> {
+        if inputs.is_empty() || outputs.is_empty() {
+            return Err(format_err!("a transaction needs at least one input and one output"));
+        }
+        let mut vout = Vec::new();
+        for (address, amount) in outputs {
+            if *amount <= 0 {
+                return Err(format_err!("invalid amount {} for {}", amount, address));
+            }
+            vout.push(TXOutput::new(*amount, address.clone())?);
+        }
         let mut tx = Transaction {
             id: String::new(),
-            vin,
+            vin: inputs
+                .iter()
+                .map(|(txid, vout)| TXInput {
+                    txid: txid.clone(),
+                    vout: *vout,
+                    signature: Vec::new(),
+                    pub_key: Vec::new(),
+                    sequence: SEQUENCE_FINAL,
+                })
+                .collect(),
             vout,
         };
-        tx.id = tx.hash()?;
-        utxo.blockchain
-            .sign_transacton(&mut tx, &wallet.secret_key)?;
+        tx.id = tx.compute_id()?;
         Ok(tx)
     }
 
+    /// SignWithWallets signs every input spending an output that belongs to one of
+    /// the wallets. prevouts maps (txid, vout) to the output each input spends.
+    /// Returns true when every input carries a signature
+    pub fn sign_with_wallets(
+        &mut self,
+        wallets: &Wallets,
+        prevouts: &HashMap<(String, i32), TXOutput>,
+    ) -> Result<bool> {
+        wallets.ensure_unlocked()?;
+        let mut keys = Vec::new();
+        for vin in &self.vin {
+            let key = prevouts
+                .get(&(vin.txid.clone(), vin.vout))
+                .and_then(|out| wallets.get_wallet(&address_from_pub_key_hash(&out.pub_key_hash)))
+                .map(|w| (w.secret_key.clone(), w.public_key.clone()));
+            keys.push(key);
+        }
+
+        for (in_id, key) in keys.iter().enumerate() {
+            if let Some((_, public_key)) = key {
+                self.vin[in_id].pub_key = public_key.clone();
+            }
+        }
+        self.id = self.compute_id()?;
+
+        for (in_id, key) in keys.iter().enumerate() {
+            if let Some((secret_key, _)) = key {
+                let vin = &self.vin[in_id];
+                let prev = &prevouts[&(vin.txid.clone(), vin.vout)];
+                let pub_key_hash = prev.pub_key_hash.clone();
+                self.sign_input(in_id, secret_key, &pub_key_hash)?;
+            }
+        }
+        Ok(self.vin.iter().all(|vin| !vin.signature.is_empty()))
+    }
+
     /// NewCoinbaseTX creates a new coinbase transaction
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
-        info!("new coinbase Transaction to: {}", to);
+    pub fn new_coinbase(to: String, data: String) -> Result<Transaction> {
+        Transaction::new_coinbase_with_fees(to, data, 0)
+    }
+
+    /// NewCoinbaseWithFees creates a coinbase transaction paying the subsidy plus
+    /// the fees of the block transactions
+    pub fn new_coinbase_with_fees(to: String, mut data: String, fees: i32) -> Result<Transaction> {
+        info!("new coinbase Transaction to: {} fees: {}", to, fees);
         let mut key: [u8; 32] = [0; 32];
         if data.is_empty() {
-            let mut rand = OsRng::default();
+            let mut rand = OsRng;
             rand.fill_bytes(&mut key);
             data = format!("Reward to '{}'", to);
         }
@@ -117,43 +300,74 @@ impl Transaction {
                 vout: -1,
                 signature: Vec::new(),
                 pub_key,
+                sequence: SEQUENCE_FINAL,
             }],
-            vout: vec![TXOutput::new(SUBSIDY, to)?],
+            vout: vec![TXOutput::new(SUBSIDY + fees, to)?],
         };
         tx.id = tx.hash()?;
         Ok(tx)
     }
 
+    /// ToHex serializes the transaction as a hex string
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(serialize(self)?))
+    }
+
+    /// FromHex parses a transaction serialized by to_hex
+    pub fn from_hex(data: &str) -> Result<Transaction> {
+        let bytes = hex::decode(data.trim())?;
+        let tx = bincode::deserialize(&bytes)?;
+        Ok(tx)
+    }
+
+    /// Size is the length of the serialized transaction in bytes
+    pub fn size(&self) -> Result<usize> {
+        Ok(bincode::serialized_size(self)? as usize)
+    }
+
+    /// SignedSize is the size the transaction has once every input is signed
+    pub fn signed_size(&self) -> Result<usize> {
+        let mut copy = self.clone();
+        for vin in &mut copy.vin {
+            vin.signature = vec![0; 64];
+            vin.pub_key = vec![0; 32];
+        }
+        copy.id = copy.hash()?;
+        copy.size()
+    }
+
+    /// SignalsRbf tells whether the transaction may be replaced in the mempool by
+    /// one paying a higher fee
+    pub fn signals_rbf(&self) -> bool {
+        self.vin.iter().any(|vin| vin.sequence < SEQUENCE_FINAL - 1)
+    }
+
     /// IsCoinbase checks whether the transaction is coinbase
     pub fn is_coinbase(&self) -> bool {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 
     /// Verify verifies signatures of Transaction inputs
-    pub fn verify(&self, prev_TXs: HashMap<String, Transaction>) -> Result<bool> {
+    pub fn verify(&self, prev_txs: HashMap<String, Transaction>) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
         }
 
-        for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
-            }
-        }
-
         let mut tx_copy = self.trim_copy();
 
         for in_id in 0..self.vin.len() {
-            let prev_Tx = prev_TXs.get(&self.vin[in_id].txid).unwrap();
+            let prev_out = prev_output(&prev_txs, &self.vin[in_id])?;
+            // ed25519 panics on keys and signatures of the wrong length
+            if self.vin[in_id].pub_key.len() != 32 || self.vin[in_id].signature.len() != 64 {
+                return Ok(false);
+            }
             tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[self.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
+            tx_copy.vin[in_id].pub_key = prev_out.pub_key_hash.clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.vin[in_id].pub_key = Vec::new();
 
             if !ed25519::verify(
-                &tx_copy.id.as_bytes(),
+                tx_copy.id.as_bytes(),
                 &self.vin[in_id].pub_key,
                 &self.vin[in_id].signature,
             ) {
@@ -168,35 +382,36 @@ impl Transaction {
     pub fn sign(
         &mut self,
         private_key: &[u8],
-        prev_TXs: HashMap<String, Transaction>,
+        prev_txs: HashMap<String, Transaction>,
     ) -> Result<()> {
         if self.is_coinbase() {
             return Ok(());
         }
 
-        for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
-            }
+        for in_id in 0..self.vin.len() {
+            let pub_key_hash = prev_output(&prev_txs, &self.vin[in_id])?.pub_key_hash.clone();
+            self.sign_input(in_id, private_key, &pub_key_hash)?;
         }
 
-        let mut tx_copy = self.trim_copy();
-
-        for in_id in 0..tx_copy.vin.len() {
-            let prev_Tx = prev_TXs.get(&tx_copy.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
-            self.vin[in_id].signature = signature.to_vec();
-        }
+        Ok(())
+    }
 
+    /// SignInput signs input in_id, which spends an output locked with prev_pub_key_hash
+    pub fn sign_input(&mut self, in_id: usize, private_key: &[u8], prev_pub_key_hash: &[u8]) -> Result<()> {
+        let hash = self.signature_hash(in_id, prev_pub_key_hash)?;
+        let signature = ed25519::signature(hash.as_bytes(), private_key);
+        self.vin[in_id].signature = signature.to_vec();
         Ok(())
     }
 
+    /// SignatureHash returns the message input in_id signs, it does not depend on
+    /// the signatures or public keys of any input
+    pub fn signature_hash(&self, in_id: usize, prev_pub_key_hash: &[u8]) -> Result<String> {
+        let mut tx_copy = self.trim_copy();
+        tx_copy.vin[in_id].pub_key = prev_pub_key_hash.to_vec();
+        tx_copy.hash()
+    }
+
     /// Hash returns the hash of the Transaction
     pub fn hash(&self) -> Result<String> {
         let mut copy = self.clone();
@@ -207,6 +422,16 @@ impl Transaction {
         Ok(hasher.result_str())
     }
 
+    /// ComputeId hashes the transaction without its signatures, so the id can be
+    /// computed before the inputs are signed
+    pub fn compute_id(&self) -> Result<String> {
+        let mut copy = self.clone();
+        for vin in &mut copy.vin {
+            vin.signature.clear();
+        }
+        copy.hash()
+    }
+
     /// TrimmedCopy creates a trimmed copy of Transaction to be used in signing
     fn trim_copy(&self) -> Transaction {
         let mut vin = Vec::new();
@@ -215,9 +440,10 @@ impl Transaction {
         for v in &self.vin {
             vin.push(TXInput {
                 txid: v.txid.clone(),
-                vout: v.vout.clone(),
+                vout: v.vout,
                 signature: Vec::new(),
                 pub_key: Vec::new(),
+                sequence: v.sequence,
             })
         }
 
@@ -236,6 +462,21 @@ impl Transaction {
     }
 }
 
+/// prev_output returns the output vin spends, out of the transactions it spends outputs of
+fn prev_output<'a>(prev_txs: &'a HashMap<String, Transaction>, vin: &TXInput) -> Result<&'a TXOutput> {
+    let prev_tx = match prev_txs.get(&vin.txid) {
+        Some(tx) if !tx.id.is_empty() => tx,
+        _ => return Err(format_err!("ERROR: Previous transaction is not correct")),
+    };
+    if vin.vout < 0 {
+        return Err(format_err!("input spends negative output {} of {}", vin.vout, vin.txid));
+    }
+    match prev_tx.vout.get(vin.vout as usize) {
+        Some(out) => Ok(out),
+        None => Err(format_err!("transaction {} has no output {}", vin.txid, vin.vout)),
+    }
+}
+
 impl TXOutput {
     /// IsLockedWithKey checks if the output can be used by the owner of the pubkey
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
@@ -266,7 +507,7 @@ mod test {
     #[test]
     fn test_signature() {
         let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
+        let wa1 = ws.create_wallet().unwrap();
         let w = ws.get_wallet(&wa1).unwrap().clone();
         ws.save_all().unwrap();
         drop(ws);
@@ -278,4 +519,55 @@ mod test {
         let signature = ed25519::signature(tx.id.as_bytes(), &w.secret_key);
         assert!(ed25519::verify(tx.id.as_bytes(), &w.public_key, &signature));
     }
+
+    #[test]
+    fn test_sign_raw() {
+        let mut ws = Wallets::new().unwrap();
+        let from = ws.create_wallet().unwrap();
+        let to = ws.create_wallet().unwrap();
+        let prev = Transaction::new_coinbase(from.clone(), String::from("raw")).unwrap();
+
+        let mut tx = Transaction::new_raw(&[(prev.id.clone(), 0)], &[(to, 5)]).unwrap();
+        let tx_hex = tx.to_hex().unwrap();
+        assert_eq!(Transaction::from_hex(&tx_hex).unwrap().id, tx.id);
+        let signed_size = tx.signed_size().unwrap();
+
+        let mut prevouts = HashMap::new();
+        assert!(!tx.sign_with_wallets(&ws, &prevouts).unwrap());
+        prevouts.insert((prev.id.clone(), 0), prev.vout[0].clone());
+        assert!(tx.sign_with_wallets(&ws, &prevouts).unwrap());
+        // the fee is computed before signing, from the size once signed
+        assert_eq!(tx.size().unwrap(), signed_size);
+
+        let mut prev_txs = HashMap::new();
+        prev_txs.insert(prev.id.clone(), prev);
+        assert!(tx.verify(prev_txs.clone()).unwrap());
+
+        // malformed inputs from a peer are rejected, not panicked on
+        let mut bad = tx.clone();
+        bad.vin[0].signature.truncate(10);
+        assert!(!bad.verify(prev_txs.clone()).unwrap());
+        for vout in [-1, 5] {
+            let mut bad = tx.clone();
+            bad.vin[0].vout = vout;
+            assert!(bad.verify(prev_txs.clone()).is_err());
+        }
+        assert!(tx.verify(HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let mut outs = TXOutputs {
+            outputs: Vec::new(),
+            indexes: Vec::new(),
+            height: 5,
+            coinbase: true,
+        };
+        assert!(!outs.is_mature(6, 100));
+        assert!(!outs.is_mature(104, 100));
+        assert!(outs.is_mature(105, 100));
+        assert!(outs.is_mature(6, 1));
+        outs.coinbase = false;
+        assert!(outs.is_mature(6, 100));
+    }
 }
\ No newline at end of file
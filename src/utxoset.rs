@@ -3,10 +3,17 @@
 use super::*;
 use crate::block::*;
 use crate::blockchain::*;
+use crate::coinselect::Coin;
 use crate::transaction::*;
 use bincode::{deserialize, serialize};
-use sled;
-use std::collections::HashMap;
+use log::info;
+use std::collections::{HashMap, HashSet};
+
+/// UTXO_VERSION is the format of the stored outputs, bumped when TXOutputs changes
+const UTXO_VERSION: u32 = 2;
+/// META_TREE keeps the version apart from the outputs iterated in the default tree
+const META_TREE: &str = "meta";
+const VERSION_KEY: &str = "version";
 
 /// UTXOSet represents UTXO set
 pub struct UTXOSet {
@@ -14,7 +21,23 @@ pub struct UTXOSet {
 }
 
 impl UTXOSet {
-    /// FindUnspentTransactions returns a list of transactions containing unspent outputs
+    /// NewUTXOSet opens the UTXO set of blockchain, reindexing it when it was
+    /// written in an older format
+    pub fn new(blockchain: Blockchain) -> Result<UTXOSet> {
+        let utxo_set = UTXOSet { blockchain };
+        let version = match sled::open("data/utxos")?.open_tree(META_TREE)?.get(VERSION_KEY)? {
+            Some(v) => Some(deserialize::<u32>(&v)?),
+            None => None,
+        };
+        if version != Some(UTXO_VERSION) {
+            info!("UTXO set is in an older format, reindexing");
+            utxo_set.reindex()?;
+        }
+        Ok(utxo_set)
+    }
+
+    /// FindUnspentTransactions returns a list of transactions containing unspent outputs,
+    /// leaving out the coinbase outputs not mature enough to be spent in the next block
     pub fn find_spendable_outputs(
         &self,
         pub_key_hash: &[u8],
@@ -22,20 +45,24 @@ impl UTXOSet {
     ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
         let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
         let mut accumulated = 0;
+        let height = self.blockchain.get_best_height()? + 1;
 
         let db = sled::open("data/utxos")?;
         for kv in db.iter() {
             let (k, v) = kv?;
             let txid = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
+            let outs: TXOutputs = deserialize(&v)?;
+            if !outs.is_mature(height, self.blockchain.coinbase_maturity) {
+                continue;
+            }
 
             for out_idx in 0..outs.outputs.len() {
                 if outs.outputs[out_idx].is_locked_with_key(pub_key_hash) && accumulated < amount {
                     accumulated += outs.outputs[out_idx].value;
                     match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
+                        Some(v) => v.push(outs.indexes[out_idx]),
                         None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
+                            unspent_outputs.insert(txid.clone(), vec![outs.indexes[out_idx]]);
                         }
                     }
                 }
@@ -45,20 +72,47 @@ impl UTXOSet {
         Ok((accumulated, unspent_outputs))
     }
 
+    /// FindSpendableCoins returns every output locked with one of pub_key_hashes that
+    /// can be spent in the next block
+    pub fn find_spendable_coins(&self, pub_key_hashes: &HashSet<Vec<u8>>) -> Result<Vec<Coin>> {
+        let mut coins = Vec::new();
+        let height = self.blockchain.get_best_height()? + 1;
+
+        let db = sled::open("data/utxos")?;
+        for kv in db.iter() {
+            let (k, v) = kv?;
+            let txid = String::from_utf8(k.to_vec())?;
+            let outs: TXOutputs = deserialize(&v)?;
+            if !outs.is_mature(height, self.blockchain.coinbase_maturity) {
+                continue;
+            }
+
+            for (out, vout) in outs.outputs.into_iter().zip(outs.indexes) {
+                if pub_key_hashes.contains(&out.pub_key_hash) {
+                    coins.push(Coin {
+                        txid: txid.clone(),
+                        vout,
+                        output: out,
+                    });
+                }
+            }
+        }
+
+        Ok(coins)
+    }
+
     /// FindUTXO finds UTXO for a public key hash
-    pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
+    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<Vec<TXOutput>> {
+        let mut utxos = Vec::new();
         let db = sled::open("data/utxos")?;
 
         for kv in db.iter() {
             let (_, v) = kv?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
+            let outs: TXOutputs = deserialize(&v)?;
 
             for out in outs.outputs {
                 if out.is_locked_with_key(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+                    utxos.push(out.clone())
                 }
             }
         }
@@ -82,11 +136,12 @@ impl UTXOSet {
         std::fs::remove_dir_all("data/utxos").ok();
         let db = sled::open("data/utxos")?;
 
-        let utxos = self.blockchain.find_UTXO();
+        let utxos = self.blockchain.find_utxo();
 
         for (txid, outs) in utxos {
             db.insert(txid.as_bytes(), serialize(&outs)?)?;
         }
+        db.open_tree(META_TREE)?.insert(VERSION_KEY, serialize(&UTXO_VERSION)?)?;
 
         Ok(())
     }
@@ -100,14 +155,10 @@ impl UTXOSet {
         for tx in block.get_transaction() {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
-                    };
-                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
+                    let mut update_outputs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap())?;
+                    if let Some(pos) = update_outputs.indexes.iter().position(|i| *i == vin.vout) {
+                        update_outputs.outputs.remove(pos);
+                        update_outputs.indexes.remove(pos);
                     }
 
                     if update_outputs.outputs.is_empty() {
@@ -118,12 +169,12 @@ impl UTXOSet {
                 }
             }
 
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
+            let new_outputs = TXOutputs {
+                outputs: tx.vout.clone(),
+                indexes: (0..tx.vout.len() as i32).collect(),
+                height: block.get_height(),
+                coinbase: tx.is_coinbase(),
             };
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
-            }
 
             db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
         }
@@ -1,4 +1,9 @@
+use crypto::aes::{self, KeySize};
 use crypto::ed25519;
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::symmetriccipher::SynchronousStreamCipher;
 use bitcoincash_addr::{Address, HashType, Scheme};
 use crypto::digest::Digest;
 use crypto::ripemd160::Ripemd160;
@@ -6,11 +11,30 @@ use crypto::sha2::Sha256;
 use rand::RngCore;
 use rand::rngs::OsRng;
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::error::Result;
+use crate::hdwallet::derive_wallet_key;
+use bip39::Mnemonic;
+use failure::format_err;
 use std::string::String;
+use std::time::SystemTime;
 use log::info;
 
+/// CRYPT_KEY is the entry of the wallet database holding the encryption parameters
+const CRYPT_KEY: &str = "CRYPT";
+/// HD_CHAIN_KEY is the entry of the wallet database holding the HD seed
+const HD_CHAIN_KEY: &str = "HDCHAIN";
+/// WATCH_ONLY_TREE holds the addresses tracked without their private key
+const WATCH_ONLY_TREE: &str = "watchonly";
+/// CHANGE_TREE maps the wallet transactions to the position of their change output
+const CHANGE_TREE: &str = "change";
+/// DEFAULT_GAP_LIMIT is how many unused addresses in a row end a restore scan
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+/// scrypt cost parameters for new encrypted wallets
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
     pub secret_key: Vec<u8>,
@@ -22,7 +46,12 @@ impl Wallet {
     fn new() -> Self{
         let mut key: [u8; 32] = [0; 32];
         OsRng.fill_bytes(&mut key);
-        let (secret_key, public_key) = ed25519::keypair(&key);
+        Wallet::from_seed(&key)
+    }
+
+    /// FromSeed creates the wallet whose key pair is generated from a 32 byte seed
+    fn from_seed(key: &[u8]) -> Self {
+        let (secret_key, public_key) = ed25519::keypair(key);
         let secret_key = secret_key.to_vec();
         let public_key = public_key.to_vec();
         Wallet{
@@ -33,19 +62,24 @@ impl Wallet {
     pub fn get_address(&self) -> String {
         let mut pub_hash = self.public_key.clone();
         hash_pub_key(&mut pub_hash);
-        let address = Address{
-            body: pub_hash,
-            scheme: Scheme::Base58,
-            hash_type: HashType::Script,
-            ..Default::default()
-        };
-        // 0 O 1 I = removed for similarities sake.
-        address.encode().unwrap()
+        address_from_pub_key_hash(&pub_hash)
     }
 
     
 }
 
+/// address_from_pub_key_hash encodes a public key hash as a wallet address
+pub fn address_from_pub_key_hash(pub_key_hash: &[u8]) -> String {
+    let address = Address{
+        body: pub_key_hash.to_vec(),
+        scheme: Scheme::Base58,
+        hash_type: HashType::Script,
+        ..Default::default()
+    };
+    // 0 O 1 I = removed for similarities sake.
+    address.encode().unwrap()
+}
+
 pub fn hash_pub_key(pub_key: &mut Vec<u8>){
     let mut hasher1 = Sha256::new();
     hasher1.input(pub_key);
@@ -56,8 +90,37 @@ pub fn hash_pub_key(pub_key: &mut Vec<u8>){
     hasher2.result(pub_key);
 }
 
+/// WalletCrypt describes how the secret keys of an encrypted wallet are protected
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct WalletCrypt {
+    salt: Vec<u8>,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    /// check is a MAC proving the passphrase derives the right key
+    check: Vec<u8>,
+}
+
+/// HdChain is the master seed every new address is derived from
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct HdChain {
+    seed: Vec<u8>,
+    /// next_index is the index of the next address to derive
+    next_index: u32,
+}
+
+/// Wallets holds the wallets of the node. When the store is encrypted and
+/// locked the secret keys are left empty
 pub struct Wallets{
+    /// wallets are the spendable addresses with their keys
     wallets: HashMap<String, Wallet>,
+    /// watch_only are tracked addresses the wallet can not spend from
+    watch_only: HashSet<String>,
+    hd: Option<HdChain>,
+    crypt: Option<WalletCrypt>,
+    key: Option<Vec<u8>>,
+    /// encrypted holds the stored secret keys and HD seed of an encrypted wallet
+    encrypted: HashMap<String, Vec<u8>>,
 }
 
 
@@ -66,49 +129,549 @@ impl Wallets {
     pub fn new() -> Result<Wallets> {
         let mut wlt = Wallets{
             wallets: HashMap::<String, Wallet>::new(),
+            watch_only: HashSet::new(),
+            hd: None,
+            crypt: None,
+            key: None,
+            encrypted: HashMap::new(),
         };
 
-        let db = sled::open("data/wallets")?;
+        let db = open_db()?;
+        if let Some(crypt) = db.get(CRYPT_KEY)? {
+            wlt.crypt = Some(bincode::deserialize(&crypt)?);
+        }
+        if let Some(hd) = db.get(HD_CHAIN_KEY)? {
+            let hd: HdChain = bincode::deserialize(&hd)?;
+            if wlt.crypt.is_some() {
+                wlt.encrypted.insert(HD_CHAIN_KEY.to_string(), hd.seed.clone());
+            }
+            wlt.hd = Some(hd);
+        }
         for item in db.into_iter(){
             let i = item?;
             let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = bincode::deserialize(&i.1.to_vec())?;
+            if address == CRYPT_KEY || address == HD_CHAIN_KEY {
+                continue;
+            }
+            let wallet: Wallet = bincode::deserialize(&i.1)?;
+            if wlt.crypt.is_some() {
+                wlt.encrypted.insert(address.clone(), wallet.secret_key.clone());
+            }
             wlt.wallets.insert(address, wallet);
         }
+        for item in db.open_tree(WATCH_ONLY_TREE)?.iter() {
+            let (address, _) = item?;
+            wlt.watch_only.insert(String::from_utf8(address.to_vec())?);
+        }
         drop(db);
         Ok(wlt)
     }
 
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
+    /// IsLocked tells whether the secret keys are unavailable
+    pub fn is_locked(&self) -> bool {
+        self.crypt.is_some() && self.key.is_none()
+    }
+
+    /// EnsureUnlocked fails when the secret keys are unavailable for signing
+    pub fn ensure_unlocked(&self) -> Result<()> {
+        if self.is_locked() {
+            return Err(format_err!("wallet is locked, unlock it first with walletpassphrase"));
+        }
+        Ok(())
+    }
+
+    /// EncryptWallet encrypts every secret key with a key derived from passphrase
+    /// and stores them, the wallet stays locked afterwards
+    pub fn encrypt_wallet(&mut self, passphrase: &str) -> Result<()> {
+        if self.crypt.is_some() {
+            return Err(format_err!("wallet is already encrypted"));
+        }
+        if passphrase.is_empty() {
+            return Err(format_err!("passphrase can not be empty"));
+        }
+        let mut salt: [u8; 16] = [0; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut crypt = WalletCrypt {
+            salt: salt.to_vec(),
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            check: Vec::new(),
+        };
+        let key = derive_key(passphrase, &crypt);
+        crypt.check = passphrase_check(&key);
+        self.crypt = Some(crypt);
+        self.key = Some(key);
+        self.save_all()?;
+        info!("wallet encrypted");
+        self.lock();
+        *self = Wallets::new()?;
+        Ok(())
+    }
+
+    /// Unlock makes the secret keys available, for as long as this value lives
+    pub fn unlock(&mut self, passphrase: &str) -> Result<()> {
+        let crypt = match &self.crypt {
+            Some(c) => c,
+            None => return Err(format_err!("wallet is not encrypted")),
+        };
+        let key = derive_key(passphrase, crypt);
+        self.unlock_with_key(key)
+    }
+
+    /// UnlockWithKey makes the secret keys available with a key derived by Unlock
+    pub fn unlock_with_key(&mut self, mut key: Vec<u8>) -> Result<()> {
+        let crypt = match &self.crypt {
+            Some(c) => c,
+            None => return Err(format_err!("wallet is not encrypted")),
+        };
+        if !check_key(&key, crypt) {
+            wipe(&mut key);
+            return Err(format_err!("the wallet passphrase entered was incorrect"));
+        }
+        self.key = Some(key);
+        self.decrypt_all()
+    }
+
+    /// MasterKey is the key the secret keys are encrypted with, while unlocked
+    pub fn master_key(&self) -> Option<&[u8]> {
+        self.key.as_deref()
+    }
+
+    /// Lock wipes the secret keys and the key they are encrypted with
+    pub fn lock(&mut self) {
+        if self.crypt.is_some() {
+            if let Some(key) = &mut self.key {
+                wipe(key);
+            }
+            self.key = None;
+            for wallet in self.wallets.values_mut() {
+                wipe(&mut wallet.secret_key);
+            }
+            if let Some(hd) = &mut self.hd {
+                wipe(&mut hd.seed);
+            }
+        }
+    }
+
+    /// decrypt_all fills in the secret keys when the key is known, and clears
+    /// them otherwise
+    fn decrypt_all(&mut self) -> Result<()> {
+        for (address, wallet) in self.wallets.iter_mut() {
+            if let Some(secret_key) = reveal(&self.key, &self.encrypted, address)? {
+                wallet.secret_key = secret_key;
+            }
+        }
+        if let Some(hd) = &mut self.hd {
+            if let Some(seed) = reveal(&self.key, &self.encrypted, HD_CHAIN_KEY)? {
+                hd.seed = seed;
+            }
+        }
+        Ok(())
+    }
+
+    /// seal returns what to store for the secret name, None when the stored
+    /// copy must be kept as is
+    fn seal(&self, name: &str, plain: &[u8]) -> Result<Option<Vec<u8>>> {
+        match (&self.crypt, &self.key) {
+            (None, _) => Ok(Some(plain.to_vec())),
+            (Some(_), Some(key)) => Ok(Some(encrypt(key, plain))),
+            // the stored copy of a locked wallet is already encrypted
+            (Some(_), None) if self.encrypted.contains_key(name) => Ok(None),
+            (Some(_), None) => Err(format_err!("wallet is locked, unlock it first with walletpassphrase")),
+        }
+    }
+
+    /// NewHdSeed generates the master seed new addresses are derived from and
+    /// returns its mnemonic, the only backup needed from now on
+    pub fn new_hd_seed(&mut self) -> Result<String> {
+        if self.hd.is_some() {
+            return Err(format_err!("wallet already has an HD seed"));
+        }
+        self.ensure_unlocked()?;
+        let mut entropy: [u8; 32] = [0; 32];
+        OsRng.fill_bytes(&mut entropy);
+        let mnemonic = Mnemonic::from_entropy(&entropy)?;
+        self.hd = Some(HdChain {
+            seed: mnemonic.to_seed("").to_vec(),
+            next_index: 0,
+        });
+        info!("new HD seed");
+        Ok(mnemonic.to_string())
+    }
+
+    /// RestoreHdSeed restores the master seed from its mnemonic and derives its
+    /// addresses until gap_limit consecutive ones were never used. Returns the
+    /// restored addresses
+    pub fn restore_hd_seed<F: Fn(&str) -> bool>(
+        &mut self,
+        phrase: &str,
+        is_used: F,
+        gap_limit: u32,
+    ) -> Result<Vec<String>> {
+        if self.hd.is_some() {
+            return Err(format_err!("wallet already has an HD seed"));
+        }
+        self.ensure_unlocked()?;
+        let seed = Mnemonic::parse(phrase)?.to_seed("").to_vec();
+
+        let mut derived = Vec::new();
+        let mut used = 0;
+        while (derived.len() as u32) < used + gap_limit {
+            let wallet = Wallet::from_seed(&derive_wallet_key(&seed, derived.len() as u32));
+            if is_used(&wallet.get_address()) {
+                used = derived.len() as u32 + 1;
+            }
+            derived.push(wallet);
+        }
+        derived.truncate(used as usize);
+
+        let mut addresses = Vec::new();
+        for wallet in derived {
+            let address = wallet.get_address();
+            self.wallets.insert(address.clone(), wallet);
+            addresses.push(address);
+        }
+        self.hd = Some(HdChain { seed, next_index: used });
+        info!("restored {} addresses from the HD seed", used);
+        Ok(addresses)
+    }
+
+    /// CreateWallet adds a new address, derived from the HD seed when there is one
+    pub fn create_wallet(&mut self) -> Result<String> {
+        let wallet = match &mut self.hd {
+            Some(hd) => {
+                if hd.seed.is_empty() {
+                    return Err(format_err!("wallet is locked, unlock it first with walletpassphrase"));
+                }
+                let wallet = Wallet::from_seed(&derive_wallet_key(&hd.seed, hd.next_index));
+                hd.next_index += 1;
+                wallet
+            }
+            None => Wallet::new(),
+        };
         let address = wallet.get_address();
         self.wallets.insert(address.clone(), wallet);
         info!("Create wallet: {}", address);
-        address
+        Ok(address)
     }
 
     pub fn get_all_address(&self) -> Vec<String> {
         let mut addresses = Vec::new();
-        for(address, _) in &self.wallets{
+        for address in self.wallets.keys() {
             addresses.push(address.clone())
         }
         addresses
     }
 
+    /// GetWatchOnlyAddresses returns the addresses tracked without a private key
+    pub fn get_watch_only_addresses(&self) -> Vec<String> {
+        self.watch_only.iter().cloned().collect()
+    }
+
+    /// GetOwnedAddresses returns the spendable and watch-only addresses
+    pub fn get_owned_addresses(&self) -> HashSet<String> {
+        self.wallets.keys().chain(self.watch_only.iter()).cloned().collect()
+    }
+
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
         self.wallets.get(address)
     }
 
+    /// DumpPrivateKey returns the private key of address as hex
+    pub fn dump_private_key(&self, address: &str) -> Result<String> {
+        self.ensure_unlocked()?;
+        match self.wallets.get(address) {
+            Some(wallet) => Ok(hex::encode(&wallet.secret_key[..32])),
+            None => Err(format_err!("no private key for address {}", address)),
+        }
+    }
+
+    /// ImportPrivateKey adds the key exported by dumpprivkey, returns its address
+    pub fn import_private_key(&mut self, private_key: &str) -> Result<String> {
+        self.ensure_unlocked()?;
+        let seed = hex::decode(private_key.trim())?;
+        if seed.len() != 32 {
+            return Err(format_err!("invalid private key length {}", seed.len()));
+        }
+        let wallet = Wallet::from_seed(&seed);
+        let address = wallet.get_address();
+        self.watch_only.remove(&address);
+        self.wallets.insert(address.clone(), wallet);
+        info!("Import private key of: {}", address);
+        Ok(address)
+    }
+
+    /// ImportAddress watches address without being able to spend from it
+    pub fn import_address(&mut self, address: &str) -> Result<()> {
+        if Address::decode(address).is_err() {
+            return Err(format_err!("invalid address {}", address));
+        }
+        if self.wallets.contains_key(address) {
+            return Err(format_err!("the wallet already has the private key of {}", address));
+        }
+        self.watch_only.insert(address.to_string());
+        info!("Import watch-only address: {}", address);
+        Ok(())
+    }
+
+    /// RecordChange remembers that output vout of the wallet transaction txid is its change
+    pub fn record_change(&self, txid: &str, vout: i32) -> Result<()> {
+        let db = open_db()?;
+        db.open_tree(CHANGE_TREE)?.insert(txid, bincode::serialize(&vout)?)?;
+        db.flush()?;
+        Ok(())
+    }
+
+    /// ChangeOutput returns the position of the change output of the wallet
+    /// transaction txid, None when it has none or was not built by the wallet
+    pub fn change_output(&self, txid: &str) -> Result<Option<i32>> {
+        let db = open_db()?;
+        let vout = match db.open_tree(CHANGE_TREE)?.get(txid)? {
+            Some(data) => Some(bincode::deserialize(&data)?),
+            None => None,
+        };
+        Ok(vout)
+    }
+
+    /// Dump writes the HD seed, every private key and watch-only address as text
+    pub fn dump(&self) -> Result<String> {
+        self.ensure_unlocked()?;
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        let mut out = format!("# wallet dump created at {}\n", now);
+        out += "# keep this file secret, it gives full control over the funds\n";
+        if let Some(hd) = &self.hd {
+            out += &format!("hdseed {} {}\n", hex::encode(&hd.seed), hd.next_index);
+        }
+        let mut addresses = self.get_all_address();
+        addresses.sort();
+        for address in addresses {
+            out += &format!("key {} {}\n", self.dump_private_key(&address)?, address);
+        }
+        let mut watch_only = self.get_watch_only_addresses();
+        watch_only.sort();
+        for address in watch_only {
+            out += &format!("watch {}\n", address);
+        }
+        Ok(out)
+    }
+
+    /// ImportDump restores what dump wrote, returns how many entries were added
+    pub fn import_dump(&mut self, data: &str) -> Result<usize> {
+        let mut imported = 0;
+        for line in data.lines().map(|l| l.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["hdseed", seed, next_index] => {
+                    if self.hd.is_some() {
+                        info!("wallet already has an HD seed, skip the dumped one");
+                        continue;
+                    }
+                    self.ensure_unlocked()?;
+                    self.hd = Some(HdChain {
+                        seed: hex::decode(seed)?,
+                        next_index: next_index.parse()?,
+                    });
+                }
+                ["key", private_key, address] => {
+                    if self.import_private_key(private_key)? != *address {
+                        return Err(format_err!("the dumped key does not match address {}", address));
+                    }
+                }
+                ["watch", address] => {
+                    if self.wallets.contains_key(*address) {
+                        continue;
+                    }
+                    self.import_address(address)?;
+                }
+                _ => return Err(format_err!("invalid wallet dump line: {}", line)),
+            }
+            imported += 1;
+        }
+        Ok(imported)
+    }
+
     pub fn save_all(&self) -> Result<()>{
-        let db = sled::open("data/wallets")?;
+        let db = open_db()?;
 
+        // the keys and the encryption parameters are written at once so the
+        // store never mixes plain and encrypted keys
+        let mut batch = sled::Batch::default();
+        if let Some(crypt) = &self.crypt {
+            batch.insert(CRYPT_KEY, bincode::serialize(crypt)?);
+        }
+        if let Some(hd) = &self.hd {
+            let seed = match self.seal(HD_CHAIN_KEY, &hd.seed)? {
+                Some(seed) => seed,
+                None => self.encrypted[HD_CHAIN_KEY].clone(),
+            };
+            let hd = HdChain { seed, next_index: hd.next_index };
+            batch.insert(HD_CHAIN_KEY, bincode::serialize(&hd)?);
+        }
         for(address, wallet) in &self.wallets{
-            let data = bincode::serialize(wallet)?;
-            db.insert(address, data)?;
+            let secret_key = match self.seal(address, &wallet.secret_key)? {
+                Some(secret_key) => secret_key,
+                None => continue,
+            };
+            let data = bincode::serialize(&Wallet {
+                secret_key,
+                public_key: wallet.public_key.clone(),
+            })?;
+            batch.insert(address.as_str(), data);
+        }
+        db.apply_batch(batch)?;
+
+        let watch_only = db.open_tree(WATCH_ONLY_TREE)?;
+        for item in watch_only.iter() {
+            let (address, _) = item?;
+            if !self.watch_only.contains(std::str::from_utf8(&address)?) {
+                watch_only.remove(address)?;
+            }
+        }
+        for address in &self.watch_only {
+            watch_only.insert(address.as_str(), vec![])?;
         }
 
         db.flush()?;
         drop(db);
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl Drop for Wallets {
+    fn drop(&mut self) {
+        self.lock();
+    }
+}
+
+/// derive_key stretches passphrase into an encryption key and a MAC key
+fn derive_key(passphrase: &str, crypt: &WalletCrypt) -> Vec<u8> {
+    let params = ScryptParams::new(crypt.log_n, crypt.r, crypt.p);
+    let mut key = vec![0; 64];
+    scrypt(passphrase.as_bytes(), &crypt.salt, &params, &mut key);
+    key
+}
+
+/// open_db opens the wallet database without a background flusher, so the
+/// lock is released as soon as the handle is dropped and the next open in the
+/// same process does not race with it. save_all flushes explicitly
+pub(crate) fn open_db() -> Result<sled::Db> {
+    let db = sled::Config::new()
+        .path("data/wallets")
+        .flush_every_ms(None)
+        .open()?;
+    Ok(db)
+}
+
+/// reveal decrypts the stored secret name when the key is known, returns an
+/// empty secret when it is not and None when nothing is stored under name
+fn reveal(key: &Option<Vec<u8>>, encrypted: &HashMap<String, Vec<u8>>, name: &str) -> Result<Option<Vec<u8>>> {
+    let stored = match encrypted.get(name) {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    match key {
+        Some(key) => match decrypt(key, stored) {
+            Some(plain) => Ok(Some(plain)),
+            None => Err(format_err!("could not decrypt the secret of {}", name)),
+        },
+        None => Ok(Some(Vec::new())),
+    }
+}
+
+fn mac(key: &[u8], data: &[u8]) -> MacResult {
+    let mut hmac = Hmac::new(Sha256::new(), &key[32..]);
+    hmac.input(data);
+    hmac.result()
+}
+
+fn passphrase_check(key: &[u8]) -> Vec<u8> {
+    mac(key, b"wallet passphrase check").code().to_vec()
+}
+
+fn check_key(key: &[u8], crypt: &WalletCrypt) -> bool {
+    key.len() == 64 && mac(key, b"wallet passphrase check") == MacResult::new(&crypt.check)
+}
+
+/// wipe overwrites a secret before emptying it, so it does not linger in freed memory
+pub fn wipe(secret: &mut Vec<u8>) {
+    for b in secret.iter_mut() {
+        // volatile so the compiler can not drop the stores to memory about to be freed
+        unsafe { std::ptr::write_volatile(b, 0) };
+    }
+    secret.clear();
+}
+
+/// encrypt returns iv || AES-256-CTR ciphertext || HMAC-SHA256 of both
+fn encrypt(key: &[u8], plain: &[u8]) -> Vec<u8> {
+    let mut iv: [u8; 16] = [0; 16];
+    OsRng.fill_bytes(&mut iv);
+    let mut data = iv.to_vec();
+    let mut cipher_text = vec![0; plain.len()];
+    aes::ctr(KeySize::KeySize256, &key[..32], &iv).process(plain, &mut cipher_text);
+    data.extend(cipher_text);
+    let tag = mac(key, &data);
+    data.extend_from_slice(tag.code());
+    data
+}
+
+/// decrypt reverses encrypt, None when the data was not encrypted with key
+fn decrypt(key: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 16 + 32 {
+        return None;
+    }
+    let (body, tag) = data.split_at(data.len() - 32);
+    if mac(key, body) != MacResult::new(tag) {
+        return None;
+    }
+    let (iv, cipher_text) = body.split_at(16);
+    let mut plain = vec![0; cipher_text.len()];
+    aes::ctr(KeySize::KeySize256, &key[..32], iv).process(cipher_text, &mut plain);
+    Some(plain)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let crypt = WalletCrypt {
+            salt: vec![1; 16],
+            log_n: 4,
+            r: 8,
+            p: 1,
+            check: Vec::new(),
+        };
+        let key = derive_key("correct horse", &crypt);
+        let wallet = Wallet::new();
+        let data = encrypt(&key, &wallet.secret_key);
+        assert_ne!(&data[16..16 + wallet.secret_key.len()], &wallet.secret_key[..]);
+        assert_eq!(decrypt(&key, &data).unwrap(), wallet.secret_key);
+
+        let other = derive_key("wrong horse", &crypt);
+        assert!(decrypt(&other, &data).is_none());
+        let crypt = WalletCrypt { check: passphrase_check(&key), ..crypt };
+        assert!(check_key(&key, &crypt));
+        assert!(!check_key(&other, &crypt));
+    }
+
+    #[test]
+    fn test_import_dump() {
+        let mut ws = Wallets::new().unwrap();
+        let address = ws.create_wallet().unwrap();
+        let watched = Wallet::new().get_address();
+        ws.import_address(&watched).unwrap();
+        assert!(ws.import_address(&address).is_err());
+        let dump = ws.dump().unwrap();
+
+        let mut restored = Wallets::new().unwrap();
+        restored.import_dump(&dump).unwrap();
+        assert_eq!(restored.get_wallet(&address), ws.get_wallet(&address));
+        assert!(restored.get_watch_only_addresses().contains(&watched));
+        assert!(restored.get_wallet(&watched).is_none());
+    }
+}